@@ -0,0 +1,186 @@
+use std::{io, str::FromStr};
+
+use crate::iodirect::output_file::OutputFile;
+
+/// Width of the time buckets keys get counted in, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bucket(u64);
+
+impl Bucket {
+    pub const SECOND: Self = Self(1000);
+    pub const MINUTE: Self = Self(60 * 1000);
+    pub const HOUR: Self = Self(60 * 60 * 1000);
+    pub const DAY: Self = Self(24 * 60 * 60 * 1000);
+
+    /// Returns the start of the bucket `millis` falls into.
+    #[inline]
+    pub fn start_of(self, millis: u64) -> u64 {
+        millis - millis % self.0
+    }
+}
+
+impl FromStr for Bucket {
+    type Err = String;
+
+    /// Parses `<n><unit>` where unit is one of ms, s, m, h or d, e.g. 1m.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.bytes().take_while(u8::is_ascii_digit).count();
+        let (n, unit) = s.split_at(digits);
+        let n: u64 = n
+            .parse()
+            .map_err(|_| format!("bucket needs to start with a number: {s}"))?;
+        let unit = match unit {
+            "ms" => 1,
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            "d" => 24 * 60 * 60 * 1000,
+            _ => {
+                return Err(format!(
+                    "unknown bucket unit: {s}, expected ms, s, m, h or d"
+                ))
+            }
+        };
+        match n.checked_mul(unit) {
+            Some(0) => Err(format!("bucket can not be empty: {s}")),
+            Some(millis) => Ok(Self(millis)),
+            None => Err(format!("bucket is too large: {s}")),
+        }
+    }
+}
+
+/// Counts merged epoch millisecond keys per time bucket and writes one
+/// `<bucket start><TAB><count>` line per non-empty bucket. With gaps
+/// enabled, the smallest and largest difference between consecutive
+/// keys in the bucket get appended, or `-` for buckets with a single key.
+///
+/// Keys have to arrive sorted, either ascending or descending.
+pub struct Aggregator {
+    bucket: Bucket,
+    gaps: bool,
+    cur: Option<BucketStats>,
+    line: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct BucketStats {
+    start: u64,
+    count: u64,
+    last: u64,
+    min_gap: u64,
+    max_gap: u64,
+}
+
+impl Aggregator {
+    pub fn new(bucket: Bucket) -> Self {
+        Self {
+            bucket,
+            gaps: false,
+            cur: None,
+            line: Vec::with_capacity(64),
+        }
+    }
+
+    pub fn with_gaps(mut self, gaps: bool) -> Self {
+        self.gaps = gaps;
+        self
+    }
+
+    #[inline]
+    pub fn push(&mut self, millis: u64, dest: &mut OutputFile) -> io::Result<()> {
+        let start = self.bucket.start_of(millis);
+        match &mut self.cur {
+            Some(cur) if cur.start == start => {
+                let gap = cur.last.abs_diff(millis);
+                cur.min_gap = cur.min_gap.min(gap);
+                cur.max_gap = cur.max_gap.max(gap);
+                cur.count += 1;
+                cur.last = millis;
+                Ok(())
+            }
+            _ => {
+                self.flush(dest)?;
+                self.cur = Some(BucketStats {
+                    start,
+                    count: 1,
+                    last: millis,
+                    min_gap: u64::MAX,
+                    max_gap: 0,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes the last bucket. Needs to be called once all keys have
+    /// been pushed.
+    pub fn finish(&mut self, dest: &mut OutputFile) -> io::Result<()> {
+        self.flush(dest)
+    }
+
+    fn flush(&mut self, dest: &mut OutputFile) -> io::Result<()> {
+        use std::io::Write;
+
+        let Some(cur) = self.cur.take() else {
+            return Ok(());
+        };
+        self.line.clear();
+        write!(&mut self.line, "{}\t{}", cur.start, cur.count)?;
+        if self.gaps {
+            if cur.count > 1 {
+                write!(&mut self.line, "\t{}\t{}", cur.min_gap, cur.max_gap)?;
+            } else {
+                self.line.extend_from_slice(b"\t-\t-");
+            }
+        }
+        self.line.push(b'\n');
+        dest.write_bytes(&self.line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_bucket() {
+        assert_eq!(Ok(Bucket::SECOND), "1s".parse());
+        assert_eq!(Ok(Bucket::MINUTE), "1m".parse());
+        assert_eq!(Ok(Bucket::HOUR), "1h".parse());
+        assert_eq!(Ok(Bucket::DAY), "1d".parse());
+        assert_eq!(Ok(Bucket(15 * 60 * 1000)), "15m".parse());
+        assert_eq!(Ok(Bucket(250)), "250ms".parse());
+        assert!("m".parse::<Bucket>().is_err());
+        assert!("0s".parse::<Bucket>().is_err());
+        assert!("1w".parse::<Bucket>().is_err());
+        assert!("213503982334601d".parse::<Bucket>().is_err());
+        assert!("18446744073709551615s".parse::<Bucket>().is_err());
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let path = crate::test_path("aggregate.txt");
+        {
+            let mut output = OutputFile::new(path.to_str().unwrap(), 0).unwrap();
+            let mut agg = Aggregator::new(Bucket::SECOND).with_gaps(true);
+            for millis in [
+                1671670171000,
+                1671670171236,
+                1671670171237,
+                1671670171999,
+                1671670173500,
+            ] {
+                agg.push(millis, &mut output).unwrap();
+            }
+            agg.finish(&mut output).unwrap();
+        }
+
+        assert_eq!(
+            "1671670171000\t4\t1\t762\n\
+             1671670173000\t1\t-\t-\n",
+            fs::read_to_string(&path).unwrap()
+        );
+    }
+}
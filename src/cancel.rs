@@ -0,0 +1,129 @@
+//! Stopping a merge early, see `SortingWriter::with_cancel`.
+
+use std::{
+    error::Error,
+    fmt,
+    io::{self, ErrorKind},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::iodirect::temp_files;
+
+/// Shared flag that makes a merge stop at the next line boundary, with an
+/// error for which `is_cancelled` holds. Clones cancel the same merge.
+#[derive(Debug, Default, Clone)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes the first SIGINT or SIGTERM cancel this token instead of
+    /// ending the process. Another one ends it right away, which removes
+    /// the temp files of outputs that were not published yet.
+    pub fn cancel_on_signals(&self) {
+        temp_files::cancel_on_signals(&self.0);
+    }
+
+    /// Makes SIGINT and SIGTERM end the process again, e.g. once a merge
+    /// is past the point where it would stop. That still removes the temp
+    /// files of outputs that were not published yet.
+    pub fn exit_on_signals(&self) {
+        temp_files::exit_on_signals(&self.0);
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Returns the error for a cancelled merge once this is cancelled.
+    pub fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            return Err(io::Error::new(ErrorKind::Interrupted, Cancelled));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `e` comes from a `CancelToken` rather than from the IO.
+pub fn is_cancelled(e: &io::Error) -> bool {
+    matches!(e.get_ref(), Some(inner) if inner.is::<Cancelled>())
+}
+
+#[derive(Debug)]
+struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("merge cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        iodirect::{
+            file_io::MemFile,
+            output_file::{OutputFile, OutputOptions},
+            sorted_file::SortedFile,
+        },
+        merge::SortingWriter,
+    };
+
+    #[test]
+    fn test_cancel() {
+        let contents: Vec<u8> = (0..100_000_u64)
+            .flat_map(|i| format!("{:013}\n", 1671670000000 + i).into_bytes())
+            .collect();
+        let input = SortedFile::<u64, _>::with_reader(MemFile::new(&contents), 14, false).unwrap();
+
+        let cancel = CancelToken::new();
+        let mut wr = SortingWriter::new(vec![input]).with_cancel(cancel.clone());
+        cancel.cancel();
+        let file = MemFile::default();
+        let mut output = OutputFile::with_file_io(file.clone(), OutputOptions::default());
+        let e = wr.write_to(&mut output).unwrap_err();
+        assert!(is_cancelled(&e), "{e}");
+        assert!(!is_cancelled(&io::Error::from(ErrorKind::Interrupted)));
+
+        // stopped in between two lines
+        output.discard().unwrap();
+        let written = file.contents();
+        assert_eq!(wr.stats().lines * 14, written.len() as u64);
+        assert!(written.len() < contents.len());
+        assert_eq!(&contents[..written.len()], &written[..]);
+    }
+
+    #[test]
+    fn test_cancel_after_merge() {
+        let path = crate::test_path("cancel.src");
+        let input =
+            SortedFile::<u64, _>::with_reader(MemFile::new(b"1671670000000\n"), 14, false).unwrap();
+        let sidecar = OutputFile::new_atomic(path.to_str().unwrap(), 0).unwrap();
+
+        let cancel = CancelToken::new();
+        let mut wr = SortingWriter::new(vec![input])
+            .with_cancel(cancel.clone())
+            .with_sidecar(sidecar);
+        let mut output = OutputFile::with_file_io(MemFile::default(), OutputOptions::default());
+        wr.write_to(&mut output).unwrap();
+        // e.g. a signal while the output gets flushed
+        cancel.cancel();
+        let e = wr.finish().unwrap_err();
+        assert!(is_cancelled(&e), "{e}");
+        output.discard().unwrap();
+        drop(wr);
+        assert!(!path.exists());
+    }
+}
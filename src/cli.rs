@@ -0,0 +1,647 @@
+use std::{fmt::Display, fs, io, str::FromStr};
+
+use mpchal4::{
+    aggregate::Bucket,
+    generate::{Gaps, Generator, MAX_KEY, MIN_KEY},
+    iodirect::{
+        detect_order,
+        key_index::IndexSpacing,
+        output_file::{is_stream, stdout_is_pipe, OutputFile, OutputOptions, UtcOffset},
+        split_output::SplitBy,
+        Order,
+    },
+    merge::TieBreak,
+    query::parse_key,
+};
+
+/// Exit status when verification found a problem or a query found
+/// nothing.
+pub const EXIT_FAILED: i32 = 1;
+/// Exit status when the command line does not make sense.
+pub const EXIT_USAGE: i32 = 2;
+/// Exit status when the command failed, e.g. because an input could not
+/// be read.
+pub const EXIT_ERROR: i32 = 3;
+/// Exit status when a merge got stopped by SIGINT or SIGTERM, like a
+/// shell reports a process killed by SIGINT.
+pub const EXIT_CANCELLED: i32 = 130;
+
+pub const USAGE: &str = "\
+usage: mpchal4 <command> [options] [args]
+
+commands:
+  merge     merge sorted files into one, the default when no command is given
+  verify    check an output against its checksums or its inputs
+  generate  write a sorted file of random keys
+  stats     show the line count, key range and order of files
+  query     print the lines of a sorted file within a range of keys
+  help      show the help of a command
+
+Wherever a command takes files, @list reads their paths from the file
+list, one per line. Empty lines and lines starting with # get skipped.
+
+exit status: 0 on success, 1 when verify fails or query finds nothing,
+2 for a bad command line, 3 for any other error and 130 when a merge got
+stopped by SIGINT or SIGTERM. A second signal ends it right away.";
+
+const MERGE_USAGE: &str = "\
+usage: mpchal4 merge [options] <input>...
+
+  -o, --output PATH       where to write the merge, - for stdout. Defaults to
+                          stdout when it is a pipe and to result.txt otherwise
+  --mode lines|records    fixed width lines, or `key<TAB>payload` records of any
+                          length (--records for short)
+  --input-order auto|asc|desc
+  --output-order asc|desc
+  --tie-break input|reverse|round-robin
+  --write-mode buffered|sync|direct|writeback:MiB
+  --io-uring              keep several writes in flight
+  --checksum              write a .crc32c sidecar
+  --tag, --labels A,B,..  append the index or label of the input to every line
+  --tag-sidecar           write the input index of every line to a .src sidecar
+  --index, --index-lines N
+                          write a sparse key index to a .idx sidecar
+  --aggregate BUCKET      write counts per bucket, e.g. 1m, instead of the lines
+  --gaps                  add the smallest and largest gap to every bucket
+  --iso, --utc-offset +HH:MM
+                          write keys as ISO-8601 timestamps
+  --split-lines N, --split-bytes N, --split-bucket BUCKET
+  --split-template NAME   spread the output over several files
+  --progress              print how far the merge got to stderr every second
+  --report PATH           write statistics of the run as JSON, - for stderr
+  --bench-defaults        merge files/{2,4,8,10,20,40}m.txt when no inputs are
+                          given, for profiling";
+
+const VERIFY_USAGE: &str = "\
+usage: mpchal4 verify [--records] <file> [<input>...]
+
+Without inputs, checks <file> against its .crc32c sidecar. With inputs,
+checks that <file> is a merge of them.";
+
+const GENERATE_USAGE: &str = "\
+usage: mpchal4 generate [options] <file>
+
+  --lines N               number of lines, 1000000 by default
+  --seed N                the same seed always gives the same file
+  --start MS              key of the first line, 13 digits
+  --gaps fixed:N|uniform:N|exp:N
+                          distance between consecutive keys
+  --duplicates RATE       chance of a line repeating the key before it
+  --no-final-newline";
+
+const STATS_USAGE: &str = "\
+usage: mpchal4 stats [--records] <file>...";
+
+const QUERY_USAGE: &str = "\
+usage: mpchal4 query <file> <key> [<last key>]
+
+Prints the lines with keys from <key> up to and including <last key>, or
+only those with <key>. Uses the .idx sidecar of <file> when there is one.";
+
+/// What to run, as given on the command line.
+#[derive(Debug)]
+pub enum Command {
+    Merge(Args),
+    Verify {
+        path: String,
+        inputs: Vec<String>,
+        records: bool,
+    },
+    Generate {
+        path: String,
+        generator: Generator,
+    },
+    Stats {
+        paths: Vec<String>,
+        records: bool,
+    },
+    Query {
+        path: String,
+        from: u128,
+        to: u128,
+    },
+    /// Print this and exit.
+    Help(&'static str),
+}
+
+impl Command {
+    /// Parses the arguments without the program name. Errors are meant
+    /// for the user, followed by the usage.
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut args = args.peekable();
+        let Some(command) = args.peek() else {
+            return Err("no command given".to_string());
+        };
+        let parse: fn(&mut dyn Iterator<Item = String>) -> Result<Self, String> =
+            match command.as_str() {
+                "help" | "-h" | "--help" => {
+                    args.next();
+                    let usage = match args.next().as_deref() {
+                        Some("merge") => MERGE_USAGE,
+                        Some("verify") => VERIFY_USAGE,
+                        Some("generate") => GENERATE_USAGE,
+                        Some("stats") => STATS_USAGE,
+                        Some("query") => QUERY_USAGE,
+                        _ => USAGE,
+                    };
+                    return Ok(Self::Help(usage));
+                }
+                "merge" => Self::parse_merge,
+                "verify" => Self::parse_verify,
+                "generate" => Self::parse_generate,
+                "stats" => Self::parse_stats,
+                "query" => Self::parse_query,
+                // `mpchal4 a.txt b.txt` merges
+                _ => return Self::parse_merge(&mut args),
+            };
+        args.next();
+        parse(&mut args)
+    }
+
+    fn parse_merge(args: &mut dyn Iterator<Item = String>) -> Result<Self, String> {
+        let mut ret = Args::default();
+        let mut bench_defaults = false;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Self::Help(MERGE_USAGE)),
+                "-o" | "--output" => ret.output_path = Some(value(args, &arg, "a path")?),
+                "--write-mode" => ret.output_opts.mode = parsed(args, &arg, "a mode")?,
+                "--io-uring" => ret.output_opts.io_uring = true,
+                "--checksum" => ret.output_opts.checksum = true,
+                "--records" => ret.records = true,
+                "--mode" => {
+                    ret.records = match value(args, &arg, "lines or records")?.as_str() {
+                        "lines" => false,
+                        "records" => true,
+                        mode => {
+                            return Err(format!("unknown mode: {mode}, expected lines or records"))
+                        }
+                    }
+                }
+                "--tag" => ret.tag = Some(TagMode::Index),
+                "--labels" => {
+                    let labels = value(args, &arg, "a comma separated list")?;
+                    ret.tag = Some(TagMode::Labels(
+                        labels.split(',').map(str::to_string).collect(),
+                    ));
+                }
+                "--tag-sidecar" => ret.tag_sidecar = true,
+                "--index" => ret.index = Some(IndexSpacing::default()),
+                "--index-lines" => match parsed(args, &arg, "a positive number")? {
+                    0 => return Err("--index-lines needs a positive number".to_string()),
+                    n => ret.index = Some(IndexSpacing::Lines(n)),
+                },
+                "--tie-break" => ret.tie_break = parsed(args, &arg, "a policy")?,
+                "--input-order" => {
+                    ret.input_order = match value(args, &arg, "auto, asc or desc")?.as_str() {
+                        "auto" => None,
+                        order => Some(order.parse()?),
+                    };
+                }
+                "--output-order" => ret.output_order = parsed(args, &arg, "asc or desc")?,
+                "--aggregate" => ret.aggregate = Some(parsed(args, &arg, "a bucket, e.g. 1m")?),
+                "--gaps" => ret.gaps = true,
+                "--iso" => ret.iso = Some(UtcOffset::default()),
+                "--utc-offset" => ret.iso = Some(parsed(args, &arg, "+HH:MM")?),
+                "--split-lines" => match parsed(args, &arg, "a positive number")? {
+                    0 => return Err("--split-lines needs a positive number".to_string()),
+                    n => ret.split = Some(SplitBy::Lines(n)),
+                },
+                "--split-bytes" => match parsed(args, &arg, "a positive number")? {
+                    0 => return Err("--split-bytes needs a positive number".to_string()),
+                    n => ret.split = Some(SplitBy::Bytes(n)),
+                },
+                "--split-bucket" => {
+                    ret.split = Some(SplitBy::Bucket(parsed(args, &arg, "a bucket, e.g. 1h")?))
+                }
+                "--split-template" => ret.split_template = Some(value(args, &arg, "a file name")?),
+                "--progress" => ret.progress = true,
+                "--report" => ret.report = Some(value(args, &arg, "a path")?),
+                "--bench-defaults" => bench_defaults = true,
+                _ => push_path(arg, &mut ret.input_paths)?,
+            }
+        }
+
+        if ret.input_paths.is_empty() && bench_defaults {
+            // e.g. `mpchal4 generate --lines 2000000 files/2m.txt` creates
+            // the first
+            for pat in ["2", "4", "8", "10", "20", "40"] {
+                ret.input_paths.push(format!("files/{pat}m.txt"));
+            }
+        }
+        if ret.input_paths.is_empty() {
+            return Err("merge needs at least one input".to_string());
+        }
+        if let Some(TagMode::Labels(labels)) = &ret.tag {
+            if labels.len() != ret.input_paths.len() {
+                return Err("--labels needs exactly one label per input".to_string());
+            }
+        }
+        if ret.aggregate.is_some() {
+            // aggregation writes counts instead of lines, which none of
+            // these apply to
+            let conflicts = [
+                (ret.split.is_some(), "split output"),
+                (ret.tag.is_some(), "--tag or --labels"),
+                (ret.tag_sidecar, "--tag-sidecar"),
+                (ret.iso.is_some(), "--iso"),
+                (ret.index.is_some(), "--index"),
+            ];
+            if let Some((_, what)) = conflicts.iter().find(|(set, _)| *set) {
+                return Err(format!("--aggregate does not work with {what}"));
+            }
+        } else if ret.gaps {
+            return Err("--gaps needs --aggregate".to_string());
+        }
+        if ret.split.is_some() {
+            if ret.output_path.is_some() {
+                return Err("-o does not work with split output, use --split-template".to_string());
+            }
+            if ret.index.is_some() {
+                return Err("--index does not work with split output".to_string());
+            }
+            if ret.tag_sidecar {
+                return Err("--tag-sidecar does not work with split output".to_string());
+            }
+        } else if ret.split_template.is_some() {
+            return Err(
+                "--split-template needs --split-lines, --split-bytes or --split-bucket".to_string(),
+            );
+        }
+        for (set, flag) in [
+            (ret.tag_sidecar, "--tag-sidecar"),
+            (ret.index.is_some(), "--index"),
+        ] {
+            if set && !ret.output_is_file() {
+                return Err(format!("{flag} needs the output to go to a file"));
+            }
+        }
+        Ok(Self::Merge(ret))
+    }
+
+    fn parse_verify(args: &mut dyn Iterator<Item = String>) -> Result<Self, String> {
+        let mut records = false;
+        let mut paths = Vec::new();
+        for arg in args {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Self::Help(VERIFY_USAGE)),
+                "--records" => records = true,
+                _ => push_path(arg, &mut paths)?,
+            }
+        }
+        if paths.is_empty() {
+            return Err("verify needs a file".to_string());
+        }
+        let path = paths.remove(0);
+        Ok(Self::Verify {
+            path,
+            inputs: paths,
+            records,
+        })
+    }
+
+    fn parse_generate(args: &mut dyn Iterator<Item = String>) -> Result<Self, String> {
+        let mut path = None;
+        let mut lines = 1_000_000;
+        let mut seed = 0;
+        let mut start = None;
+        let mut gaps = Gaps::default();
+        let mut duplicates = 0.0;
+        let mut final_newline = true;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Self::Help(GENERATE_USAGE)),
+                "--lines" => lines = parsed(args, &arg, "a number")?,
+                "--seed" => seed = parsed(args, &arg, "a number")?,
+                "--start" => match parsed(args, &arg, "a number")? {
+                    start_ms if (MIN_KEY..=MAX_KEY).contains(&start_ms) => start = Some(start_ms),
+                    start_ms => return Err(format!("--start needs 13 digits: {start_ms}")),
+                },
+                "--gaps" => gaps = parsed(args, &arg, "fixed:N, uniform:N or exp:N")?,
+                "--duplicates" => match parsed(args, &arg, "a rate")? {
+                    rate if (0.0..=1.0).contains(&rate) => duplicates = rate,
+                    rate => return Err(format!("--duplicates needs a rate in 0..=1: {rate}")),
+                },
+                "--no-final-newline" => final_newline = false,
+                _ if arg.starts_with('-') => return Err(format!("unknown option: {arg}")),
+                _ if path.is_some() => return Err("generate writes only one file".to_string()),
+                _ => path = Some(arg),
+            }
+        }
+
+        let path = path.ok_or("generate needs a file")?;
+        let mut generator = Generator::new(lines)
+            .with_seed(seed)
+            .with_gaps(gaps)
+            .with_duplicates(duplicates);
+        if let Some(start) = start {
+            generator = generator.with_start(start);
+        }
+        if !final_newline {
+            generator = generator.without_final_newline();
+        }
+        generator.check_range()?;
+        Ok(Self::Generate { path, generator })
+    }
+
+    fn parse_stats(args: &mut dyn Iterator<Item = String>) -> Result<Self, String> {
+        let mut records = false;
+        let mut paths = Vec::new();
+        for arg in args {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Self::Help(STATS_USAGE)),
+                "--records" => records = true,
+                _ => push_path(arg, &mut paths)?,
+            }
+        }
+        if paths.is_empty() {
+            return Err("stats needs at least one file".to_string());
+        }
+        Ok(Self::Stats { paths, records })
+    }
+
+    fn parse_query(args: &mut dyn Iterator<Item = String>) -> Result<Self, String> {
+        let mut positional = Vec::new();
+        for arg in args {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Self::Help(QUERY_USAGE)),
+                _ if arg.starts_with('-') => return Err(format!("unknown option: {arg}")),
+                _ => positional.push(arg),
+            }
+        }
+        let (path, from, to) = match &positional[..] {
+            [path, key] => (path, key, key),
+            [path, from, to] => (path, from, to),
+            _ => return Err("query needs a file and one or two keys".to_string()),
+        };
+        Ok(Self::Query {
+            path: path.clone(),
+            from: parse_key(from)?,
+            to: parse_key(to)?,
+        })
+    }
+}
+
+/// Returns the value of the option `flag`, which is described by `what`
+/// when it is missing.
+fn value(args: &mut dyn Iterator<Item = String>, flag: &str, what: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{flag} needs {what}"))
+}
+
+fn parsed<T>(args: &mut dyn Iterator<Item = String>, flag: &str, what: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = value(args, flag, what)?;
+    value
+        .parse()
+        .map_err(|e| format!("{flag} needs {what}, got {value}: {e}"))
+}
+
+/// Adds a file given on the command line to `paths`, or all of those
+/// listed in the file for `@list`.
+fn push_path(arg: String, paths: &mut Vec<String>) -> Result<(), String> {
+    if arg.starts_with('-') && arg != "-" {
+        return Err(format!("unknown option: {arg}"));
+    }
+    let Some(list) = arg.strip_prefix('@') else {
+        paths.push(arg);
+        return Ok(());
+    };
+    let list = fs::read_to_string(list).map_err(|e| format!("failed to read {list}: {e}"))?;
+    paths.extend(
+        list.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string),
+    );
+    Ok(())
+}
+
+pub const OUTPUT_PATH: &str = "result.txt";
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub input_paths: Vec<String>,
+    // - for stdout. Defaults to stdout if that is a pipe, else result.txt
+    pub output_path: Option<String>,
+    // how the output gets to the disk
+    pub output_opts: OutputOptions,
+    // merge `key<TAB>payload` lines by their key
+    pub records: bool,
+    // append the input each line came from to the line
+    pub tag: Option<TagMode>,
+    // write the index of the input each line came from to a sidecar file
+    pub tag_sidecar: bool,
+    // write a sparse key index of the output to a sidecar file
+    pub index: Option<IndexSpacing>,
+    // which input goes first when several have the same key
+    pub tie_break: TieBreak,
+    // order of the inputs, detected per input when not given
+    pub input_order: Option<Order>,
+    pub output_order: Order,
+    // write per bucket counts instead of the merged lines
+    pub aggregate: Option<Bucket>,
+    // add min/max gaps between keys to every bucket
+    pub gaps: bool,
+    // render keys as ISO-8601 timestamps in the given offset
+    pub iso: Option<UtcOffset>,
+    // spread the output over several files named by split_template
+    pub split: Option<SplitBy>,
+    pub split_template: Option<String>,
+    // print how far the merge got every second
+    pub progress: bool,
+    // where to write the statistics of the run, - for stderr
+    pub report: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum TagMode {
+    Index,
+    Labels(Vec<String>),
+}
+
+impl Args {
+    /// Opens the output, streaming it when it goes to something other than
+    /// a regular file.
+    pub fn open_output(&self, expected_file_size: usize) -> io::Result<OutputFile> {
+        match self.output_path.as_deref() {
+            Some("-") => OutputFile::stdout(self.output_opts),
+            None if stdout_is_pipe() => OutputFile::stdout(self.output_opts),
+            Some(path) if is_stream(path) => {
+                let out = std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("failed to open {path}: {e}")))?;
+                Ok(OutputFile::sequential(out, self.output_opts))
+            }
+            path => {
+                // the previous result stays in place until the merge is
+                // complete
+                let path = path.unwrap_or(OUTPUT_PATH);
+                OutputFile::new_atomic_with(path, expected_file_size, self.output_opts)
+            }
+        }
+    }
+
+    /// Returns the tag to append to lines from each input, if any.
+    pub fn tags(&self) -> Option<Vec<Vec<u8>>> {
+        let tags = match self.tag.as_ref()? {
+            TagMode::Index => (0..self.input_paths.len())
+                .map(|i| i.to_string().into_bytes())
+                .collect(),
+            // parsing made sure there is one per input
+            TagMode::Labels(labels) => labels.iter().map(|l| l.clone().into_bytes()).collect(),
+        };
+        Some(tags)
+    }
+
+    /// Returns the path of the output, which sidecar files get named
+    /// after. Parsing made sure that it is a file when there are any.
+    pub fn sidecar_base(&self) -> &str {
+        self.output_path.as_deref().unwrap_or(OUTPUT_PATH)
+    }
+
+    /// Whether the output goes to a file rather than to stdout or a
+    /// stream, as `open_output` decides.
+    fn output_is_file(&self) -> bool {
+        match self.output_path.as_deref() {
+            Some(path) => path != "-" && !is_stream(path),
+            None => !stdout_is_pipe(),
+        }
+    }
+
+    /// Options for sidecar files, which do not get checksums of their
+    /// own.
+    pub fn sidecar_opts(&self) -> OutputOptions {
+        OutputOptions {
+            checksum: false,
+            ..self.output_opts
+        }
+    }
+
+    /// Returns whether the input has to be read from its end to match
+    /// the output order.
+    pub fn read_backwards(&self, path: &str) -> io::Result<bool> {
+        let order = match self.input_order {
+            Some(order) => order,
+            None => detect_order(path)?,
+        };
+        Ok(order != self.output_order)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Command, String> {
+        Command::parse(args.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn test_parse() {
+        let list = crate::tests::test_path("inputs.txt");
+        fs::write(&list, "# inputs\na.txt\n\n  b.txt  \n").unwrap();
+        let Ok(Command::Merge(args)) = parse(&format!(
+            "merge -o out.txt @{} c.txt --mode records --write-mode direct",
+            list.display()
+        )) else {
+            panic!("expected a merge");
+        };
+        assert_eq!(vec!["a.txt", "b.txt", "c.txt"], args.input_paths);
+        assert_eq!(Some("out.txt"), args.output_path.as_deref());
+        assert!(args.records);
+
+        // merge is the default command
+        let Ok(Command::Merge(args)) = parse("a.txt --output-order desc") else {
+            panic!("expected a merge");
+        };
+        assert_eq!(vec!["a.txt"], args.input_paths);
+        assert_eq!(Order::Descending, args.output_order);
+        let Ok(Command::Merge(args)) = parse("merge --bench-defaults") else {
+            panic!("expected a merge");
+        };
+        assert_eq!(6, args.input_paths.len());
+
+        assert!(matches!(parse("--help"), Ok(Command::Help(USAGE))));
+        assert!(matches!(
+            parse("help query"),
+            Ok(Command::Help(QUERY_USAGE))
+        ));
+        assert!(matches!(
+            parse("merge a.txt -h"),
+            Ok(Command::Help(MERGE_USAGE))
+        ));
+        assert!(matches!(
+            parse("query out.txt 12 0034"),
+            Ok(Command::Query {
+                from: 0x12,
+                to: 0x34,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse("verify --records out.txt a.txt"),
+            Ok(Command::Verify { records: true, ref inputs, .. }) if inputs.len() == 1
+        ));
+
+        let err = |args: &str| parse(args).unwrap_err();
+        assert_eq!("no command given", err(""));
+        assert_eq!("merge needs at least one input", err("merge"));
+        assert_eq!("unknown option: --frobnicate", err("a.txt --frobnicate"));
+        assert_eq!("-o needs a path", err("a.txt -o"));
+        assert!(err("a.txt --tie-break coin").starts_with("--tie-break needs a policy"));
+        assert_eq!(
+            "--index does not work with split output",
+            err("a.txt --index --split-lines 10")
+        );
+        assert_eq!(
+            "-o does not work with split output, use --split-template",
+            err("a.txt -o out.txt --split-bytes 10")
+        );
+        assert_eq!(
+            "--split-template needs --split-lines, --split-bytes or --split-bucket",
+            err("a.txt --split-template {seq}.txt")
+        );
+        for conflict in [
+            "--split-lines 10",
+            "--tag",
+            "--tag-sidecar",
+            "--iso",
+            "--index",
+        ] {
+            let msg = err(&format!("a.txt -o out.txt --aggregate 1m {conflict}"));
+            assert!(msg.starts_with("--aggregate does not work with"), "{msg}");
+        }
+        assert_eq!("--gaps needs --aggregate", err("a.txt --gaps"));
+        assert_eq!(
+            "--index needs the output to go to a file",
+            err("a.txt -o - --index")
+        );
+        assert_eq!(
+            "--labels needs exactly one label per input",
+            err("a.txt b.txt --labels x")
+        );
+        assert_eq!(
+            "--split-lines needs a positive number",
+            err("a.txt --split-lines 0")
+        );
+        assert_eq!(
+            "--split-bytes needs a positive number",
+            err("a.txt --split-bytes 0")
+        );
+        assert!(err("query out.txt 12a").starts_with("invalid key"));
+        assert_eq!(
+            "--start needs 13 digits: 5",
+            err("generate --start 5 out.txt")
+        );
+        assert!(err("stats @/nonexistent/list").starts_with("failed to read"));
+        assert_eq!(
+            "3 lines with gaps of up to 5000000000000 from 1671670171236 run past 13 digits",
+            err("generate --lines 3 --gaps fixed:5000000000000 out.txt")
+        );
+        assert!(err("generate --gaps uniform:18446744073709551615 out.txt").ends_with("13 digits"));
+    }
+}
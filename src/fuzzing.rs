@@ -0,0 +1,271 @@
+//! Differential checks of the SIMD parsers and the merge loop against
+//! plain scalar code. Both turn arbitrary bytes into valid inputs, so
+//! they can be driven by a fuzzer as well as by a seeded random driver:
+//!
+//! ```text
+//! cd fuzz && cargo +nightly fuzz run parse
+//! cd fuzz && cargo +nightly fuzz run merge
+//! ```
+//!
+//! The tests below run the seeded driver, which needs neither cargo-fuzz
+//! nor the network.
+
+use std::cmp::Reverse;
+
+use crate::{
+    iodirect::{
+        file_io::MemFile,
+        output_file::{OutputFile, OutputOptions},
+        sorted_file::SortedFile,
+        Order,
+    },
+    merge::{SortingWriter, TieBreak},
+    simd_decimal::{newline_ends, PackedKey, MAX_LINE_WIDTH, MAX_WIDE_LINE_WIDTH},
+};
+
+/// Width of the registers the parsers load. Lines get placed at every
+/// offset within one.
+const REG_BYTES: usize = 16;
+
+/// Parses `data` as fixed width lines with every SIMD parser that
+/// supports their width, whole and split into two buffers at a line
+/// boundary, and as variable length records. Panics if any result
+/// differs from the scalar one.
+pub fn check_parse(data: &[u8]) {
+    let &[width, offset, split, ref digits @ ..] = data else {
+        return;
+    };
+    let line_width = 2 + width as usize % (MAX_WIDE_LINE_WIDTH - 1);
+    let offset = offset as usize % REG_BYTES;
+    let lines = fixed_width_lines(digits, line_width);
+    let expected: Vec<u128> = lines
+        .chunks(line_width)
+        .map(|line| scalar_key(&line[..line_width - 1]))
+        .collect();
+
+    let split = split as usize % (expected.len() + 1) * line_width;
+    for (start, end) in [(0, lines.len()), (0, split), (split, lines.len())] {
+        let expected = &expected[start / line_width..end / line_width];
+        let placed = placed_at(&lines[start..end], offset);
+        let buf = &placed[offset..];
+
+        if line_width <= MAX_LINE_WIDTH {
+            let mut parsed = Vec::new();
+            u64::parser(line_width).unwrap()(buf, &mut parsed);
+            let parsed: Vec<u128> = parsed.into_iter().map(u128::from).collect();
+            assert_eq!(expected, parsed, "u64 keys of width {line_width}");
+        }
+        let mut parsed = Vec::new();
+        u128::parser(line_width).unwrap()(buf, &mut parsed);
+        assert_eq!(expected, parsed, "u128 keys of width {line_width}");
+    }
+
+    // packed keys turn back into the numbers they were parsed from
+    for (line, &key) in lines.chunks(line_width).zip(&expected) {
+        let digits = std::str::from_utf8(&line[..line_width - 1]).unwrap();
+        if let Ok(n) = digits.parse::<u64>() {
+            assert_eq!(Some(n), key.to_u64(), "{digits}");
+        }
+        assert_eq!(digits.parse::<u128>().unwrap(), key.to_u128(), "{digits}");
+    }
+
+    check_records(digits, line_width - 1, offset);
+}
+
+/// Same digits as keys of records with payloads of different lengths, so
+/// that the lines start at all kinds of offsets.
+fn check_records(digits: &[u8], key_digits: usize, offset: usize) {
+    let mut records = Vec::new();
+    let mut starts = Vec::new();
+    let mut expected = Vec::new();
+    for key in digits.chunks_exact(key_digits) {
+        starts.push(records.len() as u32);
+        expected.push(scalar_key(&ascii_digits(key)));
+        records.extend(ascii_digits(key));
+        // the key itself picks the length of the payload
+        let payload = key[0] as usize % 7;
+        records.resize(records.len() + payload, b'x');
+        records.push(b'\n');
+    }
+
+    let placed = placed_at(&records, offset);
+    let mut ends = Vec::new();
+    newline_ends(&placed[offset..], 0, &mut ends);
+    let expected_ends: Vec<u32> = records
+        .iter()
+        .enumerate()
+        .filter(|&(_, &b)| b == b'\n')
+        .map(|(i, _)| i as u32 + 1)
+        .collect();
+    assert_eq!(expected_ends, ends, "newline ends");
+
+    // every key gets a full register loaded, whatever follows the last
+    // line is padding, just like in RecordFile
+    records.resize(records.len() + 2 * REG_BYTES, 0);
+    let placed = placed_at(&records, offset);
+    let buf = &placed[offset..];
+    if key_digits <= REG_BYTES {
+        let mut parsed = Vec::new();
+        u64::parse_at(buf, &starts, key_digits, &mut parsed);
+        let parsed: Vec<u128> = parsed.into_iter().map(u128::from).collect();
+        assert_eq!(expected, parsed, "u64 records with {key_digits} digit keys");
+    }
+    let mut parsed = Vec::new();
+    u128::parse_at(buf, &starts, key_digits, &mut parsed);
+    assert_eq!(
+        expected, parsed,
+        "u128 records with {key_digits} digit keys"
+    );
+}
+
+/// Merges up to 6 inputs made from `data`, of different line widths,
+/// some read backwards, some without a final newline, through reads cut
+/// short at arbitrary sizes. Panics if the output differs from a stable
+/// sort of all the lines.
+pub fn check_merge(data: &[u8]) {
+    let &[flags, max_io, ref data @ ..] = data else {
+        return;
+    };
+    let order = if flags & 1 == 0 {
+        Order::Ascending
+    } else {
+        Order::Descending
+    };
+    let tie_break = if flags & 2 == 0 {
+        TieBreak::InputOrder
+    } else {
+        TieBreak::ReverseInputOrder
+    };
+    let max_io = 1 + max_io as usize * 3;
+
+    // every input is a header byte, a line count and the digits
+    let mut data = data;
+    let mut inputs = Vec::new();
+    let mut lines = Vec::new();
+    while let &[header, count, ref rest @ ..] = data {
+        if inputs.len() == 6 {
+            break;
+        }
+        let line_width = 2 + header as usize % (MAX_LINE_WIDTH - 1);
+        let count = (count as usize % 64).min(rest.len() / (line_width - 1));
+        let (digits, rest) = rest.split_at(count * (line_width - 1));
+        data = rest;
+
+        let mut keys: Vec<_> = digits.chunks(line_width - 1).map(ascii_digits).collect();
+        // same width, so bytes sort like the numbers
+        keys.sort_unstable();
+        let backwards = header & 0x80 != 0;
+        if (order == Order::Descending) != backwards {
+            keys.reverse();
+        }
+        let mut contents: Vec<u8> = keys
+            .iter()
+            .flat_map(|k| k.iter().chain(b"\n"))
+            .copied()
+            .collect();
+        if header & 0x40 != 0 {
+            contents.pop();
+        }
+        if backwards {
+            keys.reverse();
+        }
+        lines.push(keys);
+
+        let reader = MemFile::new(&contents).with_max_io(max_io);
+        inputs.push(
+            SortedFile::<u64, _>::with_reader(reader, line_width, backwards)
+                .expect("reading from memory failed"),
+        );
+    }
+    if inputs.is_empty() {
+        return;
+    }
+
+    // a stable sort of the lines in the order they are read, with the
+    // input that wins ties first
+    if tie_break == TieBreak::ReverseInputOrder {
+        lines.reverse();
+    }
+    let mut expected: Vec<_> = lines.into_iter().flatten().collect();
+    match order {
+        Order::Ascending => expected.sort_by_key(|line| scalar_key(line)),
+        Order::Descending => expected.sort_by_key(|line| Reverse(scalar_key(line))),
+    }
+    let expected: Vec<u8> = expected
+        .iter()
+        .flat_map(|k| k.iter().chain(b"\n"))
+        .copied()
+        .collect();
+
+    let file = MemFile::default();
+    let mut output = OutputFile::with_file_io(file.clone(), OutputOptions::default());
+    SortingWriter::new(inputs)
+        .with_order(order)
+        .with_tie_break(tie_break)
+        .write_to(&mut output)
+        .and_then(|_| output.finish())
+        .expect("merge failed");
+    assert_eq!(
+        String::from_utf8_lossy(&expected),
+        String::from_utf8_lossy(&file.contents()),
+        "{order:?} merge with {tie_break:?}"
+    );
+}
+
+/// Packs the digits the slow way: right aligned, one nibble each.
+fn scalar_key(digits: &[u8]) -> u128 {
+    digits
+        .iter()
+        .fold(0, |key, &d| key << 4 | (d - b'0') as u128)
+}
+
+fn ascii_digits(bytes: &[u8]) -> Vec<u8> {
+    bytes.iter().map(|b| b'0' + b % 10).collect()
+}
+
+/// Turns arbitrary bytes into as many complete lines of digits as fit.
+fn fixed_width_lines(bytes: &[u8], line_width: usize) -> Vec<u8> {
+    bytes
+        .chunks_exact(line_width - 1)
+        .flat_map(|digits| {
+            let mut line = ascii_digits(digits);
+            line.push(b'\n');
+            line
+        })
+        .collect()
+}
+
+/// Copies `bytes` to `offset` into an allocation that ends right after
+/// them, so that a load past the end is a read out of bounds that the
+/// sanitizer of the fuzzer notices.
+fn placed_at(bytes: &[u8], offset: usize) -> Box<[u8]> {
+    let mut placed = vec![0; offset];
+    placed.extend_from_slice(bytes);
+    placed.into_boxed_slice()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic;
+
+    use super::*;
+    use crate::generate::SplitMix64;
+
+    /// Feeds both checks inputs of random length and content. A failure
+    /// names the seed, which reproduces it.
+    #[test]
+    fn test_seeded_differential() {
+        for seed in 0..2000 {
+            let mut rng = SplitMix64(seed);
+            let len = rng.next_u64() as usize % 1200;
+            let data: Vec<u8> = (0..len).map(|_| rng.next_u64() as u8).collect();
+
+            if panic::catch_unwind(|| check_parse(&data)).is_err() {
+                panic!("check_parse failed for seed {seed}");
+            }
+            if seed % 4 == 0 && panic::catch_unwind(|| check_merge(&data)).is_err() {
+                panic!("check_merge failed for seed {seed}");
+            }
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use std::{io, str::FromStr};
+
+use crate::iodirect::{output_file::OutputFile, LINE_WIDTH_INCL_NEWLINE};
+
+/// Smallest and largest key that still has 13 digits.
+pub const MIN_KEY: u64 = 1_000_000_000_000;
+pub const MAX_KEY: u64 = 9_999_999_999_999;
+
+/// How far apart consecutive keys of a generated file are, in
+/// milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gaps {
+    /// Always the same gap.
+    Fixed(u64),
+    /// Anything from 0 up to and including the given gap.
+    Uniform(u64),
+    /// Exponentially distributed with the given mean, like the time
+    /// between independent events.
+    Exponential(f64),
+}
+
+impl Default for Gaps {
+    fn default() -> Self {
+        Self::Uniform(20)
+    }
+}
+
+impl FromStr for Gaps {
+    type Err = String;
+
+    /// Parses fixed:N, uniform:N or exp:N.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("unknown gaps: {s}, expected fixed:N, uniform:N or exp:N");
+        let (kind, n) = s.split_once(':').ok_or_else(err)?;
+        match kind {
+            "fixed" => n.parse().map(Self::Fixed).map_err(|_| err()),
+            "uniform" => n.parse().map(Self::Uniform).map_err(|_| err()),
+            "exp" => match n.parse() {
+                Ok(mean) if mean > 0.0 => Ok(Self::Exponential(mean)),
+                _ => Err(err()),
+            },
+            _ => Err(err()),
+        }
+    }
+}
+
+/// Generates sorted files of 13 digit epoch millisecond keys, one per
+/// line, like the inputs this merges. The same settings always produce
+/// the same file.
+#[derive(Debug, Clone)]
+pub struct Generator {
+    seed: u64,
+    lines: u64,
+    start: u64,
+    gaps: Gaps,
+    duplicates: f64,
+    final_newline: bool,
+}
+
+impl Generator {
+    pub fn new(lines: u64) -> Self {
+        Self {
+            seed: 0,
+            lines,
+            start: 1671670171236,
+            gaps: Gaps::default(),
+            duplicates: 0.0,
+            final_newline: true,
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Key of the first line.
+    pub fn with_start(mut self, start: u64) -> Self {
+        assert!(
+            (MIN_KEY..=MAX_KEY).contains(&start),
+            "start has to have 13 digits: {start}"
+        );
+        self.start = start;
+        self
+    }
+
+    pub fn with_gaps(mut self, gaps: Gaps) -> Self {
+        self.gaps = gaps;
+        self
+    }
+
+    /// Chance of a line repeating the key of the line before it, on top
+    /// of the gaps that happen to be 0.
+    pub fn with_duplicates(mut self, rate: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&rate),
+            "duplicate rate has to be 0..=1"
+        );
+        self.duplicates = rate;
+        self
+    }
+
+    /// Leaves out the newline after the last line.
+    pub fn without_final_newline(mut self) -> Self {
+        self.final_newline = false;
+        self
+    }
+
+    /// Fails if fixed or uniform gaps can take the keys past 13 digits.
+    /// Exponential gaps have no upper bound, `write` fails once they do.
+    pub fn check_range(&self) -> Result<(), String> {
+        let max_gap = match self.gaps {
+            Gaps::Fixed(gap) | Gaps::Uniform(gap) => gap,
+            Gaps::Exponential(_) => return Ok(()),
+        };
+        let last = max_gap
+            .checked_mul(self.lines.saturating_sub(1))
+            .and_then(|span| span.checked_add(self.start));
+        match last {
+            Some(last) if last <= MAX_KEY => Ok(()),
+            _ => Err(format!(
+                "{} lines with gaps of up to {max_gap} from {} run past 13 digits",
+                self.lines, self.start
+            )),
+        }
+    }
+
+    /// Returns the keys in order. Keys past `MAX_KEY`, see `check_range`,
+    /// are up to the caller to catch.
+    pub fn keys(&self) -> impl Iterator<Item = u64> {
+        let mut rng = SplitMix64(self.seed);
+        let generator = self.clone();
+        let mut key = self.start;
+        (0..self.lines).map(move |i| {
+            if i > 0 && rng.next_f64() >= generator.duplicates {
+                key = key.saturating_add(generator.gap(&mut rng));
+            }
+            key
+        })
+    }
+
+    fn gap(&self, rng: &mut SplitMix64) -> u64 {
+        match self.gaps {
+            Gaps::Fixed(gap) => gap,
+            Gaps::Uniform(max) => match max.checked_add(1) {
+                Some(n) => rng.next_u64() % n,
+                None => rng.next_u64(),
+            },
+            Gaps::Exponential(mean) => (-(1.0 - rng.next_f64()).ln() * mean).round() as u64,
+        }
+    }
+
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let size = self.lines as usize * LINE_WIDTH_INCL_NEWLINE;
+        let mut out = OutputFile::new_atomic(path, size)?;
+        let mut keys = self.keys().peekable();
+        while let Some(key) = keys.next() {
+            if key > MAX_KEY {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "keys ran out of 13 digits",
+                ));
+            }
+            if keys.peek().is_none() && !self.final_newline {
+                out.write_bytes(key.to_string().as_bytes())?;
+            } else {
+                out.write_u64(key)?;
+            }
+        }
+        out.finish()
+    }
+}
+
+/// Small and fast PRNG, plenty for test data.
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in 0..1.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let generator = Generator::new(1000).with_seed(7);
+        let keys: Vec<_> = generator.keys().collect();
+        assert_eq!(keys, generator.keys().collect::<Vec<_>>());
+        assert_ne!(
+            keys,
+            generator.clone().with_seed(8).keys().collect::<Vec<_>>()
+        );
+        assert_eq!(1671670171236, keys[0]);
+        assert!(keys.windows(2).all(|w| w[0] <= w[1] && w[1] - w[0] <= 20));
+
+        let fixed: Vec<_> = Generator::new(3)
+            .with_gaps(Gaps::Fixed(5))
+            .with_start(1_000_000_000_000)
+            .keys()
+            .collect();
+        assert_eq!(
+            vec![1_000_000_000_000, 1_000_000_000_005, 1_000_000_000_010],
+            fixed
+        );
+
+        let dups = Generator::new(1000)
+            .with_duplicates(0.5)
+            .with_gaps(Gaps::Fixed(1));
+        let n = dups
+            .keys()
+            .collect::<Vec<_>>()
+            .windows(2)
+            .filter(|w| w[0] == w[1])
+            .count();
+        assert!((400..600).contains(&n), "{n} duplicates");
+
+        let path = crate::test_path("generate.txt");
+        let path = path.to_str().unwrap();
+        Generator::new(2)
+            .with_gaps(Gaps::Fixed(1))
+            .without_final_newline()
+            .write(path)
+            .unwrap();
+        assert_eq!(
+            "1671670171236\n1671670171237",
+            fs::read_to_string(path).unwrap()
+        );
+
+        // the largest gaps still have to fit
+        let fits = |lines, gaps| {
+            Generator::new(lines)
+                .with_start(MAX_KEY - 10)
+                .with_gaps(gaps)
+        };
+        assert!(fits(11, Gaps::Fixed(1)).check_range().is_ok());
+        assert!(fits(12, Gaps::Fixed(1)).check_range().is_err());
+        assert!(fits(6, Gaps::Uniform(2)).check_range().is_ok());
+        assert!(fits(7, Gaps::Uniform(2)).check_range().is_err());
+        assert!(fits(3, Gaps::Uniform(u64::MAX)).check_range().is_err());
+        assert!(fits(1, Gaps::Uniform(u64::MAX)).check_range().is_ok());
+        let runaway = fits(1000, Gaps::Exponential(1.0));
+        assert!(runaway.check_range().is_ok());
+        let err = runaway.write(path).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        assert_eq!(Ok(Gaps::Exponential(2.5)), "exp:2.5".parse());
+        assert_eq!(Ok(Gaps::Uniform(9)), "uniform:9".parse());
+        assert!("exp:0".parse::<Gaps>().is_err());
+        assert!("normal:1".parse::<Gaps>().is_err());
+    }
+}
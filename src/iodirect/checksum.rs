@@ -0,0 +1,240 @@
+use std::{
+    fs,
+    io::{self, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use crate::iodirect::CHUNK_SIZE;
+
+/// Size of the chunks that get a checksum of their own, so that damage
+/// can be narrowed down to a region of the file.
+pub const CHECKSUM_CHUNK_SIZE: usize = CHUNK_SIZE;
+
+// reflected Castagnoli polynomial
+const POLY: u32 = 0x82f6_3b78;
+
+static TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = (crc >> 1) ^ (POLY & (crc & 1).wrapping_neg());
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Running CRC32C, using the SSE4.2 crc32 instruction when the cpu has
+/// it and a table otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32c(u32);
+
+impl Default for Crc32c {
+    fn default() -> Self {
+        Self(!0)
+    }
+}
+
+impl Crc32c {
+    pub fn update(&mut self, bytes: &[u8]) {
+        #[cfg(target_arch = "x86_64")]
+        if std::arch::is_x86_feature_detected!("sse4.2") {
+            self.0 = unsafe { update_sse42(self.0, bytes) };
+            return;
+        }
+        self.0 = update_table(self.0, bytes);
+    }
+
+    pub fn value(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn update_sse42(mut crc: u32, bytes: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    let words = bytes.chunks_exact(8);
+    let rem = words.remainder();
+    let mut crc64 = crc as u64;
+    for word in words {
+        crc64 = _mm_crc32_u64(crc64, u64::from_le_bytes(word.try_into().unwrap()));
+    }
+    crc = crc64 as u32;
+    for &b in rem {
+        crc = _mm_crc32_u8(crc, b);
+    }
+    crc
+}
+
+fn update_table(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &b in bytes {
+        crc = TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// CRC32C of the whole file and of every `CHECKSUM_CHUNK_SIZE` chunk of
+/// it. Gets stored next to the file as text:
+///
+/// ```text
+/// crc32c <crc of the whole file> <length>
+/// chunk <chunk size>
+/// <crc of the first chunk>
+/// ...
+/// ```
+///
+/// with the checksums as 8 hex digits.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Checksums {
+    pub crc: u32,
+    pub len: u64,
+    pub chunks: Vec<u32>,
+}
+
+impl Checksums {
+    /// Reads `path` and checksums it.
+    pub fn of_file(path: &Path) -> io::Result<Self> {
+        let mut file = fs::File::open(path)?;
+        let mut sum = Checksummer::default();
+        let mut buf = vec![0; CHECKSUM_CHUNK_SIZE];
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => return Ok(sum.finish()),
+                Ok(n) => sum.update(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns the path of the sidecar file for `path`.
+    pub fn sidecar_path(path: &Path) -> std::path::PathBuf {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(".crc32c");
+        sidecar.into()
+    }
+
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "crc32c {:08x} {}", self.crc, self.len)?;
+        writeln!(out, "chunk {CHECKSUM_CHUNK_SIZE}")?;
+        for chunk in &self.chunks {
+            writeln!(out, "{chunk:08x}")?;
+        }
+        Ok(())
+    }
+
+    pub fn parse(s: &str) -> io::Result<Self> {
+        let invalid = |what: &str| io::Error::new(ErrorKind::InvalidData, what.to_string());
+        let hex = |s: &str| u32::from_str_radix(s, 16).map_err(|_| invalid("bad checksum"));
+
+        let mut lines = s.lines();
+        let header: Vec<_> = lines.next().unwrap_or("").split(' ').collect();
+        let ["crc32c", crc, len] = header[..] else {
+            return Err(invalid("not a crc32c checksum file"));
+        };
+        let crc = hex(crc)?;
+        let len = len.parse().map_err(|_| invalid("bad length"))?;
+        if lines.next() != Some(format!("chunk {CHECKSUM_CHUNK_SIZE}").as_str()) {
+            return Err(invalid("unsupported chunk size"));
+        }
+        let chunks = lines.map(hex).collect::<io::Result<_>>()?;
+        Ok(Self { crc, len, chunks })
+    }
+
+    /// Returns the byte ranges of the chunks in which `actual` differs
+    /// from `self`. Chunks that only one of them has count as different.
+    pub fn damaged_ranges(&self, actual: &Self) -> Vec<(u64, u64)> {
+        let len = self.len.max(actual.len);
+        (0..self.chunks.len().max(actual.chunks.len()))
+            .filter(|&i| self.chunks.get(i) != actual.chunks.get(i))
+            .map(|i| {
+                let start = (i * CHECKSUM_CHUNK_SIZE) as u64;
+                (start, len.min(start + CHECKSUM_CHUNK_SIZE as u64))
+            })
+            .collect()
+    }
+}
+
+/// Builds `Checksums` from the bytes of a file, in order, in pieces of
+/// any size.
+#[derive(Debug, Default)]
+pub struct Checksummer {
+    whole: Crc32c,
+    chunk: Crc32c,
+    chunk_len: usize,
+    sums: Checksums,
+}
+
+impl Checksummer {
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        // two passes over the bytes, but both are much faster than
+        // the write that follows
+        self.whole.update(bytes);
+        self.sums.len += bytes.len() as u64;
+        while !bytes.is_empty() {
+            let n = bytes.len().min(CHECKSUM_CHUNK_SIZE - self.chunk_len);
+            self.chunk.update(&bytes[..n]);
+            self.chunk_len += n;
+            bytes = &bytes[n..];
+            if self.chunk_len == CHECKSUM_CHUNK_SIZE {
+                self.sums.chunks.push(self.chunk.value());
+                self.chunk = Crc32c::default();
+                self.chunk_len = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Checksums {
+        if self.chunk_len > 0 {
+            self.sums.chunks.push(self.chunk.value());
+        }
+        self.sums.crc = self.whole.value();
+        self.sums
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c() {
+        let crc = |bytes: &[u8]| {
+            let mut crc = Crc32c::default();
+            crc.update(bytes);
+            crc.value()
+        };
+        assert_eq!(0xe306_9283, crc(b"123456789"));
+        assert_eq!(0, crc(b""));
+        let bytes: Vec<u8> = (0..1000_u32).map(|i| (i * 7) as u8).collect();
+        assert_eq!(crc(&bytes), !update_table(!0, &bytes));
+
+        // pieces of any size add up to the same checksums
+        let bytes = vec![b'7'; CHECKSUM_CHUNK_SIZE * 2 + 3];
+        let mut sum = Checksummer::default();
+        for piece in bytes.chunks(CHECKSUM_CHUNK_SIZE / 3 + 1) {
+            sum.update(piece);
+        }
+        let sums = sum.finish();
+        assert_eq!(crc(&bytes), sums.crc);
+        assert_eq!(3, sums.chunks.len());
+        assert_eq!(crc(&bytes[CHECKSUM_CHUNK_SIZE * 2..]), sums.chunks[2]);
+
+        let mut text = Vec::new();
+        sums.write_to(&mut text).unwrap();
+        let parsed = Checksums::parse(std::str::from_utf8(&text).unwrap()).unwrap();
+        assert_eq!(sums, parsed);
+
+        let mut damaged = parsed.clone();
+        damaged.chunks[1] ^= 1;
+        let chunk = CHECKSUM_CHUNK_SIZE as u64;
+        assert_eq!(vec![(chunk, 2 * chunk)], sums.damaged_ranges(&damaged));
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    os::unix::fs::FileExt,
+};
+
+/// The file operations `SortedFile` and `OutputFile` need. Implemented by
+/// `fs::File`, and by `MemFile` for tests and fuzzing, which can inject
+/// faults.
+#[allow(clippy::len_without_is_empty)]
+pub trait FileIo: Send + 'static {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<usize>;
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<usize>;
+    fn len(&self) -> io::Result<u64>;
+    fn set_len(&self, len: u64) -> io::Result<()>;
+    fn sync_all(&self) -> io::Result<()>;
+    fn sync_data(&self) -> io::Result<()>;
+
+    /// The real file behind this, if any, for the things that only work
+    /// with one, like io_uring and writeback hints.
+    fn as_file(&self) -> Option<&fs::File> {
+        None
+    }
+}
+
+impl FileIo for fs::File {
+    #[inline]
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<usize> {
+        FileExt::read_at(self, buf, off)
+    }
+
+    #[inline]
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<usize> {
+        FileExt::write_at(self, buf, off)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        fs::File::set_len(self, len)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        fs::File::sync_all(self)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        fs::File::sync_data(self)
+    }
+
+    fn as_file(&self) -> Option<&fs::File> {
+        Some(self)
+    }
+}
+
+/// Reads from `off` until `buf` is full or the end of the file, retrying
+/// interrupted and short reads. Returns how much was read.
+pub fn read_full_at(file: &impl FileIo, mut buf: &mut [u8], mut off: u64) -> io::Result<usize> {
+    let mut read = 0;
+    while !buf.is_empty() {
+        match file.read_at(buf, off) {
+            Ok(0) => break, // eof
+            Ok(n) => {
+                read += n;
+                off += n as u64;
+                buf = &mut buf[n..];
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Writes all of `buf` at `off`, retrying interrupted and short writes.
+pub fn write_all_at(file: &impl FileIo, mut buf: &[u8], mut off: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match file.write_at(buf, off) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                off += n as u64;
+                buf = &buf[n..];
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(test, feature = "fuzzing"))]
+pub use mem::{Fault, MemFile};
+
+#[cfg(any(test, feature = "fuzzing"))]
+mod mem {
+    use std::{
+        collections::VecDeque,
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use super::FileIo;
+
+    /// What the next read or write of a `MemFile` does instead of
+    /// transferring as much as it can.
+    #[derive(Debug, Clone, Copy)]
+    pub enum Fault {
+        /// Fails with EINTR without transferring anything.
+        Interrupted,
+        /// Transfers at most this many bytes.
+        Short(usize),
+        /// Fails with this errno, e.g. EIO or ENOSPC.
+        Errno(i32),
+    }
+
+    /// A file in memory. Clones share the contents, so a test can hand
+    /// one to the code under test and look at the result with another.
+    #[derive(Debug, Clone, Default)]
+    pub struct MemFile {
+        data: Arc<Mutex<Vec<u8>>>,
+        faults: Arc<Mutex<VecDeque<Fault>>>,
+        // limit of every read and write, after the faults ran out
+        max_io: Option<usize>,
+        syncs: Arc<AtomicUsize>,
+    }
+
+    impl MemFile {
+        pub fn new(data: &[u8]) -> Self {
+            Self {
+                data: Arc::new(Mutex::new(data.to_vec())),
+                ..Default::default()
+            }
+        }
+
+        /// Faults for the next reads and writes, one per call.
+        pub fn with_faults(self, faults: impl IntoIterator<Item = Fault>) -> Self {
+            self.faults.lock().unwrap().extend(faults);
+            self
+        }
+
+        /// Makes every read and write short, with at most `n` bytes.
+        pub fn with_max_io(mut self, n: usize) -> Self {
+            self.max_io = Some(n);
+            self
+        }
+
+        pub fn contents(&self) -> Vec<u8> {
+            self.data.lock().unwrap().clone()
+        }
+
+        /// How often `sync_all` or `sync_data` got called.
+        pub fn syncs(&self) -> usize {
+            self.syncs.load(Ordering::Relaxed)
+        }
+
+        /// How many bytes the next call may transfer.
+        fn next_len(&self, len: usize) -> io::Result<usize> {
+            let limit = match self.faults.lock().unwrap().pop_front() {
+                Some(Fault::Interrupted) => return Err(io::ErrorKind::Interrupted.into()),
+                Some(Fault::Errno(errno)) => return Err(io::Error::from_raw_os_error(errno)),
+                Some(Fault::Short(n)) => n,
+                None => self.max_io.unwrap_or(usize::MAX),
+            };
+            Ok(len.min(limit))
+        }
+    }
+
+    impl FileIo for MemFile {
+        fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<usize> {
+            let len = self.next_len(buf.len())?;
+            let data = self.data.lock().unwrap();
+            let start = (off as usize).min(data.len());
+            let n = len.min(data.len() - start);
+            buf[..n].copy_from_slice(&data[start..start + n]);
+            Ok(n)
+        }
+
+        fn write_at(&self, buf: &[u8], off: u64) -> io::Result<usize> {
+            let n = self.next_len(buf.len())?;
+            let mut data = self.data.lock().unwrap();
+            let end = off as usize + n;
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[off as usize..end].copy_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn len(&self) -> io::Result<u64> {
+            Ok(self.data.lock().unwrap().len() as u64)
+        }
+
+        fn set_len(&self, len: u64) -> io::Result<()> {
+            self.data.lock().unwrap().resize(len as usize, 0);
+            Ok(())
+        }
+
+        fn sync_all(&self) -> io::Result<()> {
+            self.syncs.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn sync_data(&self) -> io::Result<()> {
+            self.syncs.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+}
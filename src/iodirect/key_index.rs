@@ -0,0 +1,243 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    marker::PhantomData,
+    mem,
+};
+
+use crate::{
+    iodirect::{output_file::OutputFile, Order, ALIGN},
+    simd_decimal::PackedKey,
+};
+
+// Layout of an index file, all numbers little endian:
+//
+//   0..4   magic "MPIX"
+//   4..6   format version
+//   6      width of the packed keys in bytes, 8 or 16
+//   7      order of the output, 0 for ascending, 1 for descending
+//   8..12  lines per entry, or 0 when spaced by blocks
+//   12..16 bytes per block, or 0 when spaced by lines
+//
+// followed by one (packed key, offset of the line) entry per index point,
+// the key as wide as the header says and the offset as a u64.
+const MAGIC: &[u8; 4] = b"MPIX";
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = 16;
+
+/// How far apart the lines recorded in a key index are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexSpacing {
+    /// Every n-th line, starting with the first one.
+    Lines(u32),
+    /// The first line that starts in each block of this many bytes.
+    Block(u32),
+}
+
+impl Default for IndexSpacing {
+    fn default() -> Self {
+        Self::Block(ALIGN as u32)
+    }
+}
+
+/// Writes a sparse index of the merged output: the packed key and offset
+/// of a line every so often, so that readers can jump close to a key
+/// instead of scanning the output from its start. See `KeyIndex`.
+pub struct KeyIndexWriter<K> {
+    out: OutputFile,
+    spacing: IndexSpacing,
+    lines: u64,
+    // offset at which the next block starts
+    next_block: u64,
+    entry: Vec<u8>,
+    _key: PhantomData<K>,
+}
+
+impl<K: PackedKey> KeyIndexWriter<K> {
+    pub fn new(mut out: OutputFile, spacing: IndexSpacing, order: Order) -> io::Result<Self> {
+        let (lines, block) = match spacing {
+            IndexSpacing::Lines(n) => (n, 0),
+            IndexSpacing::Block(n) => (0, n),
+        };
+        assert!(lines + block > 0, "index spacing can not be 0");
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.push(mem::size_of::<K>() as u8);
+        header.push(matches!(order, Order::Descending) as u8);
+        header.extend_from_slice(&lines.to_le_bytes());
+        header.extend_from_slice(&block.to_le_bytes());
+        out.write_bytes(&header)?;
+
+        Ok(Self {
+            out,
+            spacing,
+            lines: 0,
+            next_block: 0,
+            entry: Vec::with_capacity(mem::size_of::<K>() + 8),
+            _key: PhantomData,
+        })
+    }
+
+    /// Gets told about every line of the output, in order, and records
+    /// the ones that fall on the spacing.
+    #[inline]
+    pub fn push(&mut self, key: K, offset: u64) -> io::Result<()> {
+        let record = match self.spacing {
+            IndexSpacing::Lines(n) => self.lines % n as u64 == 0,
+            IndexSpacing::Block(n) => {
+                let record = offset >= self.next_block;
+                if record {
+                    self.next_block = (offset / n as u64 + 1) * n as u64;
+                }
+                record
+            }
+        };
+        self.lines += 1;
+        if !record {
+            return Ok(());
+        }
+
+        let key: u128 = key.into();
+        self.entry.clear();
+        self.entry
+            .extend_from_slice(&key.to_le_bytes()[..mem::size_of::<K>()]);
+        self.entry.extend_from_slice(&offset.to_le_bytes());
+        self.out.write_bytes(&self.entry)
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.out.finish()
+    }
+}
+
+/// Reads an index written by `KeyIndexWriter`.
+#[derive(Debug)]
+pub struct KeyIndex {
+    order: Order,
+    // sorted in the order of the output
+    entries: Vec<(u128, u64)>,
+}
+
+impl KeyIndex {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    fn parse(buf: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(ErrorKind::InvalidData, msg.to_string());
+        if buf.len() < HEADER_LEN || &buf[..4] != MAGIC {
+            return Err(invalid("not a key index"));
+        }
+        let version = u16::from_le_bytes([buf[4], buf[5]]);
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported key index version {version}")));
+        }
+        let key_width = buf[6] as usize;
+        if key_width != 8 && key_width != 16 {
+            return Err(invalid(&format!("unsupported key width {key_width}")));
+        }
+        let order = match buf[7] {
+            0 => Order::Ascending,
+            _ => Order::Descending,
+        };
+
+        let entries = &buf[HEADER_LEN..];
+        let entry_len = key_width + 8;
+        if entries.len() % entry_len != 0 {
+            return Err(invalid("truncated key index"));
+        }
+        let entries = entries
+            .chunks_exact(entry_len)
+            .map(|entry| {
+                let (key, offset) = entry.split_at(key_width);
+                let mut key_bytes = [0; 16];
+                key_bytes[..key_width].copy_from_slice(key);
+                (
+                    u128::from_le_bytes(key_bytes),
+                    u64::from_le_bytes(offset.try_into().unwrap()),
+                )
+            })
+            .collect();
+        Ok(Self { order, entries })
+    }
+
+    /// Returns an offset of a line in the output from which on scanning
+    /// forward reaches the first line with `key`, or the first one after
+    /// where it would be. `key` is packed like `PackedKey`, which is plain
+    /// BCD for either width.
+    pub fn seek(&self, key: impl Into<u128>) -> u64 {
+        let key = key.into();
+        // equal keys may start before the entry that has them, so start
+        // at the last entry strictly before the key
+        let idx = match self.order {
+            Order::Ascending => self.entries.partition_point(|&(k, _)| k < key),
+            Order::Descending => self.entries.partition_point(|&(k, _)| k > key),
+        };
+        match idx {
+            0 => 0,
+            idx => self.entries[idx - 1].1,
+        }
+    }
+
+    /// Order of the output the index belongs to.
+    pub fn order(&self) -> Order {
+        self.order
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_index() {
+        let path = crate::test_path("key_index.idx");
+        let path = path.to_str().unwrap();
+
+        // keys 0x10, 0x11, ... 0x19 twice each, one 14 byte line per key
+        let keys: Vec<u64> = (0x10..0x1a).flat_map(|k| [k, k]).collect();
+        let write = |spacing| {
+            let out = OutputFile::new(path, 0).unwrap();
+            let mut wr = KeyIndexWriter::<u64>::new(out, spacing, Order::Ascending).unwrap();
+            for (i, &key) in keys.iter().enumerate() {
+                wr.push(key, i as u64 * 14).unwrap();
+            }
+            wr.finish().unwrap();
+            KeyIndex::open(path).unwrap()
+        };
+
+        let idx = write(IndexSpacing::Lines(3));
+        assert_eq!(7, idx.len());
+        assert_eq!(0, idx.seek(0x05_u64));
+        assert_eq!(0, idx.seek(0x10_u64));
+        assert_eq!(0, idx.seek(0x11_u64));
+        // entries at lines 0, 3, 6, ... have keys 0x10, 0x11, 0x13
+        assert_eq!(3 * 14, idx.seek(0x12_u64));
+        assert_eq!(3 * 14, idx.seek(0x13_u64));
+        assert_eq!(18 * 14, idx.seek(0x99_u64));
+        // wide keys compare the same
+        assert_eq!(3 * 14, idx.seek(0x13_u128));
+
+        // lines starting in each 64 byte block: 0, 70, 140, 196, 266
+        let idx = write(IndexSpacing::Block(64));
+        assert_eq!(5, idx.len());
+        assert_eq!(5 * 14, idx.seek(0x13_u64));
+
+        std::fs::write(
+            path,
+            b"MPIX\x02\x00\x08\x00\x03\x00\x00\x00\x00\x00\x00\x00",
+        )
+        .unwrap();
+        assert!(KeyIndex::open(path).is_err());
+    }
+}
@@ -1,6 +1,120 @@
-pub(crate) mod output_file;
-pub(crate) mod sorted_file;
+pub mod checksum;
+pub mod file_io;
+pub mod key_index;
+pub mod output_file;
+pub mod record_file;
+pub mod sorted_file;
+pub mod split_output;
+pub(crate) mod temp_files;
+pub(crate) mod uring;
+
+use std::{
+    fs,
+    io::{self, Read},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::simd_decimal::PackedKey;
+use output_file::OutputFile;
 
 const CHUNK_SIZE: usize = 1 << 20;
 pub const ALIGN: usize = 4096;
 pub const LINE_WIDTH_INCL_NEWLINE: usize = 14;
+/// Longest line, including the newline, that record mode can handle.
+pub const MAX_RECORD_LEN: usize = 64 << 10;
+
+/// A sorted stream of lines that can be merged with others by comparing
+/// their packed keys.
+pub trait MergeInput {
+    type Key: PackedKey;
+
+    fn file_size(&self) -> u64;
+    fn peek(&self) -> Option<&Self::Key>;
+    fn peek_bytes(&self) -> Option<&[u8]>;
+    /// Moves on to the next line, which can fail to be read.
+    fn next(&mut self) -> io::Result<()>;
+    fn stats(&self) -> InputStats;
+}
+
+/// What reading an input took so far.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct InputStats {
+    /// Lines parsed, which can be ahead of the lines handed out.
+    pub lines: u64,
+    /// Bytes read from the file.
+    pub bytes: u64,
+    /// Times the read buffer got refilled.
+    pub refills: u64,
+    /// Time spent blocked in reads.
+    pub read_time: Duration,
+}
+
+/// Where merged lines end up. Sinks that spread the lines over several
+/// files may only switch files between lines, so they get told about
+/// every line, its key and its length, before its bytes get written.
+pub trait LineSink {
+    fn start_line<K: PackedKey>(&mut self, key: K, len: usize) -> io::Result<&mut OutputFile>;
+}
+
+/// Direction in which the keys of an input or the output are sorted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asc" => Ok(Self::Ascending),
+            "desc" => Ok(Self::Descending),
+            _ => Err(format!("unknown order: {s}, expected asc or desc")),
+        }
+    }
+}
+
+/// Guesses the order of a file by comparing the leading digits of its
+/// first line with the first line in the first block that differs from
+/// it. Files where all of those lines are equal count as ascending.
+pub fn detect_order(file_path: &str) -> io::Result<Order> {
+    let first_block = read_first_block(file_path)?;
+
+    // the last line might be cut off by the end of the block
+    let mut lines = first_block.split_inclusive(|&b| b == b'\n');
+    let first = lines.next().map(leading_digits).unwrap_or_default();
+    let order = lines
+        .filter(|line| line.ends_with(b"\n"))
+        .map(leading_digits)
+        .find(|&k| k != first)
+        .map_or(Order::Ascending, |k| {
+            // merges of inputs with different widths mix widths, so
+            // compare by value rather than as bytes
+            if (k.len(), k) < (first.len(), first) {
+                Order::Descending
+            } else {
+                Order::Ascending
+            }
+        });
+    Ok(order)
+}
+
+/// Reads up to `ALIGN` bytes from the start of the file, which the line
+/// width and order get detected from.
+pub(crate) fn read_first_block(file_path: &str) -> io::Result<Vec<u8>> {
+    let mut first_block = Vec::with_capacity(ALIGN);
+    fs::File::open(file_path)
+        .and_then(|f| f.take(ALIGN as u64).read_to_end(&mut first_block))
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to open {file_path}: {e}")))?;
+    Ok(first_block)
+}
+
+/// The digits a line starts with, without leading zeros.
+fn leading_digits(line: &[u8]) -> &[u8] {
+    let digits = line.iter().take_while(|b| b.is_ascii_digit()).count();
+    let zeros = line.iter().take_while(|&&b| b == b'0').count();
+    &line[zeros.min(digits)..digits]
+}
@@ -1,118 +1,489 @@
 use crate::iodirect::ALIGN;
 use crate::iodirect::CHUNK_SIZE;
+use crate::iodirect::{
+    checksum::{Checksummer, Checksums},
+    file_io::{write_all_at, FileIo},
+    temp_files::TempFile,
+    uring::Ring,
+    LineSink,
+};
+use crate::simd_decimal::{self, PackedKey};
 use std::{
     fs,
-    io::{self, Cursor, Write},
+    io::{self, Cursor, ErrorKind, Write},
+    mem,
+    path::{Path, PathBuf},
+    str::FromStr,
     sync::mpsc,
+    time::{Duration, Instant},
 };
 
-use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsFd, AsRawFd};
+
+use rustix::fs::OpenOptionsExt;
 
 use super::LINE_WIDTH_INCL_NEWLINE;
 
 pub struct OutputFile {
     cur_buf: Buf,
     io_chan: Option<mpsc::Sender<Buf>>,
-    worker: Option<std::thread::JoinHandle<()>>,
+    worker: Option<std::thread::JoinHandle<io::Result<WorkerDone>>>,
     buf_pool: mpsc::Receiver<Buf>,
 
     fmt: TimeFormatter<LINE_WIDTH_INCL_NEWLINE, 4>,
+
+    // set when writing to a temp file that replaces path once finished
+    publish: Option<(TempFile, PathBuf)>,
+    // fsync the directory once renamed, see `WriteMode::syncs_publish`
+    sync_publish: bool,
+    // bytes written so far, i.e. the offset of the next byte
+    position: u64,
+    // where the file ends up, which names the checksum sidecar
+    path: Option<PathBuf>,
+    // computed by the worker, available once it exited
+    checksums: Option<Checksums>,
+    stats: OutputStats,
+}
+
+/// What the worker thread hands back once it exited: the checksums, if
+/// asked for, and the time it spent writing.
+type WorkerDone = (Option<Checksums>, Duration);
+
+/// What writing the output took.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OutputStats {
+    pub bytes: u64,
+    /// Buffers handed to the worker thread.
+    pub buffers: u64,
+    /// Times no written buffer was back in the pool, so that a new one
+    /// had to be allocated.
+    pub new_buffers: u64,
+    /// Time the worker thread spent blocked in writes.
+    pub write_time: Duration,
+}
+
+impl std::ops::AddAssign for OutputStats {
+    fn add_assign(&mut self, other: Self) {
+        self.bytes += other.bytes;
+        self.buffers += other.buffers;
+        self.new_buffers += other.new_buffers;
+        self.write_time += other.write_time;
+    }
+}
+
+/// How the worker thread gets the data to the disk.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Write to the page cache and let the kernel write back whenever it
+    /// likes. Cheapest in terms of cpu.
+    #[default]
+    Buffered,
+    /// Like `Buffered`, but `fdatasync` before finishing.
+    BufferedSync,
+    /// Skip the page cache with O_DIRECT. The last write gets padded to
+    /// the alignment and the padding truncated away afterwards.
+    Direct,
+    /// Like `Buffered`, but start writeback every this many MiB and wait
+    /// for the window before it, which keeps the amount of dirty pages
+    /// bounded.
+    Writeback(u64),
+}
+
+impl WriteMode {
+    /// Whether a file from `new_atomic_with` gets fsynced before it is
+    /// renamed, and its directory after, so that the published file
+    /// survives a crash. Only the modes that are about getting the data
+    /// on disk pay for that.
+    pub fn syncs_publish(self) -> bool {
+        matches!(self, Self::BufferedSync | Self::Direct)
+    }
+}
+
+impl FromStr for WriteMode {
+    type Err = String;
+
+    /// Parses buffered, sync, direct or writeback:N with N in MiB.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buffered" => Ok(Self::Buffered),
+            "sync" => Ok(Self::BufferedSync),
+            "direct" => Ok(Self::Direct),
+            _ => match s.strip_prefix("writeback:").map(str::parse) {
+                Some(Ok(mib)) if mib > 0 => Ok(Self::Writeback(mib)),
+                _ => Err(format!(
+                    "unknown write mode: {s}, expected buffered, sync, direct or writeback:MiB"
+                )),
+            },
+        }
+    }
+}
+
+/// Returns whether `path` exists and is something other than a regular
+/// file, e.g. a named pipe or /dev/stdout, which needs `sequential`.
+pub fn is_stream(path: &str) -> bool {
+    matches!(fs::metadata(path), Ok(meta) if !meta.is_file())
+}
+
+/// Returns whether stdout goes to a pipe or socket rather than a terminal
+/// or file.
+pub fn stdout_is_pipe() -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    let Ok(fd) = io::stdout().as_fd().try_clone_to_owned() else {
+        return false;
+    };
+    match fs::File::from(fd).metadata() {
+        Ok(meta) => meta.file_type().is_fifo() || meta.file_type().is_socket(),
+        Err(_) => false,
+    }
+}
+
+/// How `OutputFile` writes its buffers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OutputOptions {
+    pub mode: WriteMode,
+    /// Keep several writes in flight with io_uring instead of doing one
+    /// blocking write at a time. Falls back to blocking writes when the
+    /// kernel does not allow io_uring.
+    pub io_uring: bool,
+    /// CRC32C the data in the worker before writing it, see `finish`.
+    pub checksum: bool,
 }
 
 impl OutputFile {
-    pub fn new(path: &str, expected_file_size: usize) -> OutputFile {
-        let inner = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            // .custom_flags(libc::O_DIRECT)
-            .open(path)
-            .expect("failed to create result.txt");
-        rustix::fs::fallocate(
-            &inner,
-            rustix::fs::FallocateFlags::KEEP_SIZE,
-            0,
-            expected_file_size as u64,
-        )
-        .expect("fallocate failed");
+    /// Writes straight to `path`. Whatever is written so far ends up in
+    /// the file when this is dropped.
+    pub fn new(path: &str, expected_file_size: usize) -> io::Result<OutputFile> {
+        Self::with_options(path, expected_file_size, OutputOptions::default())
+    }
+
+    pub fn with_options(
+        path: &str,
+        expected_file_size: usize,
+        opts: OutputOptions,
+    ) -> io::Result<OutputFile> {
+        Self::create(Path::new(path), expected_file_size, opts, false)
+    }
+
+    /// Writes to a temp file next to `path`, which only replaces `path`
+    /// once `finish` succeeds. Dropping this before that, or getting
+    /// interrupted, removes the temp file and leaves `path` untouched.
+    pub fn new_atomic(path: &str, expected_file_size: usize) -> io::Result<OutputFile> {
+        Self::new_atomic_with(path, expected_file_size, OutputOptions::default())
+    }
 
+    /// Same as `new_atomic`. Readers never see a partial file either way,
+    /// whether it also survives a crash depends on
+    /// `WriteMode::syncs_publish`.
+    pub fn new_atomic_with(
+        path: &str,
+        expected_file_size: usize,
+        opts: OutputOptions,
+    ) -> io::Result<OutputFile> {
+        let path = PathBuf::from(path);
+        let file_name = path.file_name().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{}: output path needs a file name", path.display()),
+            )
+        })?;
+        let mut tmp_name = std::ffi::OsString::from(".");
+        tmp_name.push(file_name);
+        tmp_name.push(format!(".tmp.{}", std::process::id()));
+        let tmp = TempFile::new(path.with_file_name(tmp_name));
+
+        let mut ret = Self::create(tmp.path(), expected_file_size, opts, true)?;
+        ret.path = Some(path.clone());
+        ret.publish = Some((tmp, path));
+        ret.sync_publish = opts.mode.syncs_publish();
+        Ok(ret)
+    }
+
+    fn create(
+        path: &Path,
+        expected_file_size: usize,
+        opts: OutputOptions,
+        atomic: bool,
+    ) -> io::Result<Self> {
+        let mut open_opts = fs::OpenOptions::new();
+        open_opts.write(true).create(true).truncate(true);
+        if opts.mode == WriteMode::Direct {
+            open_opts.custom_flags(libc::O_DIRECT);
+        }
+        let inner = open_opts.open(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to create {}: {e}", path.display()),
+            )
+        })?;
+        if expected_file_size > 0 {
+            rustix::fs::fallocate(
+                &inner,
+                rustix::fs::FallocateFlags::KEEP_SIZE,
+                0,
+                expected_file_size as u64,
+            )?;
+        }
+
+        let mut ret = Self::start(inner, opts, atomic);
+        ret.path = (!atomic).then(|| path.to_path_buf());
+        Ok(ret)
+    }
+
+    /// Writes to `inner` instead of a file that gets created by path.
+    /// Buffers get written like for `with_options`, except that io_uring
+    /// and writeback only apply when `inner` is backed by a real file.
+    pub fn with_file_io(inner: impl FileIo, opts: OutputOptions) -> OutputFile {
+        Self::start(inner, opts, false)
+    }
+
+    fn start(inner: impl FileIo, opts: OutputOptions, atomic: bool) -> Self {
         let (send, recv) = mpsc::channel();
         let io_chan: Option<mpsc::Sender<Buf>> = Some(send.clone());
 
         let (buf_pool_send, buf_pool_recv) = mpsc::channel();
 
-        let worker = std::thread::spawn(move || {
-            let mut off = 0_usize;
-            let mut padn = 0_usize;
-            for buf in recv {
-                let mut buf_len = buf.position() as usize;
-                let mut buf = buf.into_inner();
-                if buf_len % ALIGN != 0 {
-                    // this happens on the very last write
-                    assert_eq!(
-                        padn, 0,
-                        "non-aligned write is only expected once at the very end"
-                    );
-                    padn = ALIGN - buf_len % ALIGN;
-                    assert!(
-                        buf_len + padn <= buf.len(),
-                        "buf will resize, which will destroy alignment guarantees"
-                    );
-                    buf[buf_len..buf_len + padn].fill(0_u8);
-                    buf_len += padn;
+        let mut uring = None;
+        let mut cur_buf = new_buf();
+        if opts.io_uring && inner.as_file().is_some() {
+            match UringWriter::new(URING_DEPTH) {
+                Ok((writer, registered_bufs)) => {
+                    // start out with the registered buffers
+                    for buf in registered_bufs {
+                        buf_pool_send.send(buf).unwrap();
+                    }
+                    cur_buf = buf_pool_recv.recv().unwrap();
+                    uring = Some(writer);
                 }
-                inner
-                    .write_all_at(&buf[..buf_len], off as u64)
-                    .expect("worker: write_all_at failed");
-                off += buf_len;
+                Err(e) => eprintln!("io_uring is not available, using blocking writes: {e}"),
+            }
+        }
 
-                if let Err(err) = buf_pool_send.send(Cursor::new(buf)) {
-                    eprintln!("failed to send buf back in pool: {err}");
+        let mode = opts.mode;
+        let mut sum = opts.checksum.then(Checksummer::default);
+        let worker = std::thread::spawn(move || -> io::Result<WorkerDone> {
+            let mut write_time = Duration::ZERO;
+            let (off, padn) = match (uring, inner.as_file()) {
+                (Some(uring), Some(file)) => {
+                    uring.write_all(file, recv, &buf_pool_send, mode, &mut sum, &mut write_time)?
                 }
-            }
+                _ => write_blocking(
+                    &inner,
+                    recv,
+                    &buf_pool_send,
+                    mode,
+                    &mut sum,
+                    &mut write_time,
+                )?,
+            };
 
             // truncate file to expected size since we might've
             // written padding zero bytes for O_DIRECT alignment
-            rustix::fs::ftruncate(&inner, (off - padn) as u64).expect("ftruncate failed");
+            inner.set_len((off - padn) as u64)?;
+            if atomic && mode.syncs_publish() {
+                inner.sync_all()?;
+            } else if mode == WriteMode::BufferedSync {
+                inner.sync_data()?;
+            }
+            Ok((sum.map(Checksummer::finish), write_time))
         });
 
         Self {
             io_chan,
+            cur_buf,
+            worker: Some(worker),
+            buf_pool: buf_pool_recv,
+            fmt: TimeFormatter::new(),
+            publish: None,
+            sync_publish: false,
+            position: 0,
+            path: None,
+            checksums: None,
+            stats: OutputStats::default(),
+        }
+    }
+
+    /// Writes to a destination that can not seek, like a pipe, socket or
+    /// terminal. Buffers get written in order and as they are, without
+    /// preallocation or padding. Of the options only `checksum` applies.
+    pub fn sequential(mut out: impl Write + Send + 'static, opts: OutputOptions) -> OutputFile {
+        let (send, recv) = mpsc::channel::<Buf>();
+        let (buf_pool_send, buf_pool_recv) = mpsc::channel();
+
+        let mut sum = opts.checksum.then(Checksummer::default);
+        let worker = std::thread::spawn(move || -> io::Result<WorkerDone> {
+            let mut write_time = Duration::ZERO;
+            for mut buf in recv {
+                let buf_len = buf.position() as usize;
+                if let Some(sum) = &mut sum {
+                    sum.update(&buf.get_ref()[..buf_len]);
+                }
+                // bail out on errors, which makes the next send fail
+                let started = Instant::now();
+                out.write_all(&buf.get_ref()[..buf_len])?;
+                write_time += started.elapsed();
+
+                buf.set_position(0);
+                if let Err(err) = buf_pool_send.send(buf) {
+                    eprintln!("failed to send buf back in pool: {err}");
+                }
+            }
+            out.flush()?;
+            Ok((sum.map(Checksummer::finish), write_time))
+        });
+
+        Self {
+            io_chan: Some(send),
             cur_buf: new_buf(),
             worker: Some(worker),
             buf_pool: buf_pool_recv,
             fmt: TimeFormatter::new(),
+            publish: None,
+            sync_publish: false,
+            position: 0,
+            path: None,
+            checksums: None,
+            stats: OutputStats::default(),
         }
     }
 
-    #[allow(dead_code)]
+    /// Streams to stdout, see `sequential`.
+    pub fn stdout(opts: OutputOptions) -> io::Result<OutputFile> {
+        // write to the fd directly, Stdout would look for newlines
+        let fd = io::stdout().as_fd().try_clone_to_owned()?;
+        Ok(Self::sequential(fs::File::from(fd), opts))
+    }
+
+    /// Writes out everything that is still buffered and waits for it to
+    /// hit the file. Files created by `new_atomic` get renamed into
+    /// place, fsynced if `WriteMode::syncs_publish`.
+    ///
+    /// With `OutputOptions::checksum`, the checksums of the file go to a
+    /// `.crc32c` sidecar next to it, see `Checksums`. Outputs that are no
+    /// file report the checksum of everything written on stderr instead.
+    pub fn finish(self) -> io::Result<()> {
+        self.finish_with_stats().map(|_| ())
+    }
+
+    /// Same as `finish`, but also returns what writing the output took.
+    pub fn finish_with_stats(mut self) -> io::Result<OutputStats> {
+        self.close()?;
+        if let Some(sums) = self.checksums.take() {
+            match &self.path {
+                Some(path) => write_checksums(&sums, path)?,
+                None => eprintln!("crc32c {:08x} {}", sums.crc, sums.len),
+            }
+        }
+        if let Some((tmp, path)) = self.publish.take() {
+            fs::rename(tmp.path(), &path)?;
+            tmp.keep();
+            if !self.sync_publish {
+                return Ok(self.stats);
+            }
+            // make the rename itself durable
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(self.stats)
+    }
+
+    /// Stops writing and removes the file written so far, e.g. after a
+    /// merge got cancelled. Buffers that were handed to the writer thread
+    /// already still get written first, the one being filled does not.
+    /// Outputs that are no file get everything and are left as they are.
+    pub fn discard(mut self) -> io::Result<()> {
+        let is_file = self.publish.is_some() || self.path.is_some();
+        if is_file {
+            self.cur_buf.set_position(0);
+        }
+        // whatever failed does not matter for a file that goes away
+        let closed = self.close();
+        if let Some((tmp, _)) = self.publish.take() {
+            drop(tmp);
+            return Ok(());
+        }
+        match self.path.take() {
+            Some(path) => fs::remove_file(path),
+            None => closed,
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        let Some(worker) = self.worker.take() else {
+            return Ok(());
+        };
+        // write buffered lines, if any
+        let flushed = Self::flush(
+            &mut self.cur_buf,
+            &self.buf_pool,
+            self.io_chan.as_ref().unwrap(),
+            &mut self.stats,
+        );
+
+        // signal worker thread to exit by dropping the sender
+        let sender = self.io_chan.take();
+        drop(sender);
+
+        // wait for worker to exit. Its error explains why flushing
+        // failed, if it did
+        let (checksums, write_time) = worker
+            .join()
+            .map_err(|_| io::Error::new(ErrorKind::Other, "output worker panicked"))??;
+        self.checksums = checksums;
+        self.stats.bytes = self.position;
+        self.stats.write_time = write_time;
+        flushed
+    }
+
+    /// Offset in the output that the next byte written ends up at.
+    #[inline]
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
     #[inline]
     pub fn write_u64(&mut self, v: u64) -> io::Result<()> {
         self.fmt.serialized_bytes(v);
         let line = &self.fmt.last_serialized;
+        self.position += line.len() as u64;
         Self::do_write_bytes(
             line,
             &mut self.cur_buf,
             &self.buf_pool,
             &self.io_chan.as_ref().unwrap(),
+            &mut self.stats,
         )
     }
 
+    /// Writes a 4bit packed number as produced by `SortedFile::peek`
+    /// without needing the original line bytes.
+    #[inline]
+    pub fn write_packed(&mut self, packed: u64) -> io::Result<()> {
+        let line = simd_decimal::unpack_packed_4bit_line::<LINE_WIDTH_INCL_NEWLINE>(packed);
+        self.write_bytes(&line)
+    }
+
     #[inline]
-    pub fn write_bytes(&mut self, line: &[u8; LINE_WIDTH_INCL_NEWLINE]) -> io::Result<()> {
+    pub fn write_bytes(&mut self, line: &[u8]) -> io::Result<()> {
+        self.position += line.len() as u64;
         Self::do_write_bytes(
             line,
             &mut self.cur_buf,
             &self.buf_pool,
             &self.io_chan.as_ref().unwrap(),
+            &mut self.stats,
         )
     }
 
     fn do_write_bytes(
-        line: &[u8; LINE_WIDTH_INCL_NEWLINE],
+        line: &[u8],
         cur_buf: &mut Cursor<Box<[u8]>>,
         buf_pool: &mpsc::Receiver<Buf>,
         io_chan: &mpsc::Sender<Buf>,
+        stats: &mut OutputStats,
     ) -> io::Result<()> {
         let buf = cur_buf.get_ref();
         let cap = buf.len() - cur_buf.position() as usize;
@@ -120,7 +491,7 @@ impl OutputFile {
             let (partial, rem) = line.split_at(cap);
             let wr = cur_buf.write(partial).unwrap();
             assert_eq!(wr, partial.len(), "write_bytes: partial: short write");
-            Self::flush(cur_buf, &buf_pool, io_chan).expect("write_bytes: flush failed");
+            Self::flush(cur_buf, &buf_pool, io_chan, stats)?;
             let wr = cur_buf.write(rem).unwrap();
             assert_eq!(wr, rem.len(), "write_bytes: partial: short write");
             return Ok(());
@@ -133,41 +504,40 @@ impl OutputFile {
         cur_buf: &mut Cursor<Box<[u8]>>,
         buf_pool: &mpsc::Receiver<Buf>,
         io_chan: &mpsc::Sender<Buf>,
+        stats: &mut OutputStats,
     ) -> io::Result<()> {
         if cur_buf.position() == 0 {
             return Ok(());
         }
 
+        stats.buffers += 1;
         let new_buf_to_use = match buf_pool.try_recv() {
             Ok(buf) => buf,
-            Err(_) => new_buf(),
+            Err(_) => {
+                stats.new_buffers += 1;
+                new_buf()
+            }
         };
         let cur = std::mem::replace(cur_buf, new_buf_to_use);
-        io_chan.send(cur).unwrap();
-        Ok(())
+        io_chan
+            .send(cur)
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "output worker exited early"))
+    }
+}
+
+impl LineSink for OutputFile {
+    #[inline]
+    fn start_line<K: PackedKey>(&mut self, _key: K, _len: usize) -> io::Result<&mut OutputFile> {
+        Ok(self)
     }
 }
 
 impl Drop for OutputFile {
+    /// Writes what is still buffered, but only `finish` reports whether
+    /// that worked: this also runs while unwinding from an error. A temp
+    /// file of `new_atomic` goes away without getting published.
     fn drop(&mut self) {
-        // write buffered lines, if any
-        Self::flush(
-            &mut self.cur_buf,
-            &self.buf_pool,
-            self.io_chan.as_ref().unwrap(),
-        )
-        .expect("write_bytes: flush failed");
-
-        // signal worker thread to exit by dropping the sender
-        let sender = self.io_chan.take();
-        drop(sender);
-
-        // wait for worker to exit
-        self.worker
-            .take()
-            .unwrap()
-            .join()
-            .expect("drop: worker panicked");
+        let _ = self.close();
     }
 }
 
@@ -178,7 +548,6 @@ struct TimeFormatter<const LINE_WIDTH: usize, const N: usize> {
 }
 
 // 2 digit decimal look up table
-#[allow(dead_code)]
 static DEC_DIGITS_LUT: &[u8; 200] = b"0001020304050607080910111213141516171819\
       2021222324252627282930313233343536373839\
       4041424344454647484950515253545556575859\
@@ -223,6 +592,444 @@ impl<const LINE_WIDTH: usize> TimeFormatter<LINE_WIDTH, 4> {
     }
 }
 
+/// Fixed offset from UTC, in minutes, that timestamps get rendered in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UtcOffset(pub i32);
+
+impl FromStr for UtcOffset {
+    type Err = String;
+
+    /// Parses `Z` or `+HH:MM` / `-HH:MM`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "Z" {
+            return Ok(Self(0));
+        }
+        let err = || format!("invalid utc offset: {s}, expected Z or +HH:MM");
+        let (sign, hh_mm) = match s.as_bytes().first() {
+            Some(b'+') => (1, &s[1..]),
+            Some(b'-') => (-1, &s[1..]),
+            _ => return Err(err()),
+        };
+        let (hh, mm) = hh_mm.split_once(':').ok_or_else(err)?;
+        let hh: i32 = hh.parse().map_err(|_| err())?;
+        let mm: i32 = mm.parse().map_err(|_| err())?;
+        if hh > 23 || mm > 59 {
+            return Err(err());
+        }
+        Ok(Self(sign * (hh * 60 + mm)))
+    }
+}
+
+/// Renders epoch milliseconds as `2022-12-22T01:03:25.500Z`, or with the
+/// offset instead of `Z` when it isn't UTC. Similar to `TimeFormatter`,
+/// the date and time of day only get rendered again when the second
+/// changes. For sorted keys, most lines only differ in the milliseconds.
+pub struct IsoFormatter {
+    offset_millis: i64,
+    last_second: i64,
+    // date and time up to the seconds, the dot and the milliseconds,
+    // followed by the offset
+    last_serialized: Vec<u8>,
+}
+
+impl IsoFormatter {
+    pub fn new(offset: UtcOffset) -> Self {
+        let mut last_serialized = b"0000-00-00T00:00:00.000".to_vec();
+        if offset.0 == 0 {
+            last_serialized.push(b'Z');
+        } else {
+            let sign = if offset.0 < 0 { '-' } else { '+' };
+            let abs = offset.0.unsigned_abs();
+            write!(
+                &mut last_serialized,
+                "{sign}{:02}:{:02}",
+                abs / 60,
+                abs % 60
+            )
+            .unwrap();
+        }
+        Self {
+            offset_millis: offset.0 as i64 * 60 * 1000,
+            last_second: i64::MIN,
+            last_serialized,
+        }
+    }
+
+    /// Length of every rendered timestamp, for years up to 9999.
+    pub fn width(&self) -> usize {
+        self.last_serialized.len()
+    }
+
+    #[inline]
+    pub fn format(&mut self, millis: u64) -> &[u8] {
+        let local = millis as i64 + self.offset_millis;
+        let second = local.div_euclid(1000);
+        if second != self.last_second {
+            self.format_second(second);
+            self.last_second = second;
+        }
+
+        let ms = local.rem_euclid(1000) as usize;
+        let pos = self.last_serialized.len() - self.suffix_len() - 3;
+        self.last_serialized[pos] = b'0' + (ms / 100) as u8;
+        let d = (ms % 100) << 1;
+        self.last_serialized[pos + 1..pos + 3].copy_from_slice(&DEC_DIGITS_LUT[d..d + 2]);
+        &self.last_serialized
+    }
+
+    #[inline]
+    fn suffix_len(&self) -> usize {
+        if self.offset_millis == 0 {
+            1
+        } else {
+            6
+        }
+    }
+
+    fn format_second(&mut self, second: i64) {
+        let days = second.div_euclid(86400);
+        let secs = second.rem_euclid(86400);
+        let (y, m, d) = civil_from_days(days);
+
+        // keep the dot, milliseconds and offset, which follow the seconds
+        let tail_start = self.last_serialized.len() - self.suffix_len() - 4;
+        let tail = self.last_serialized.split_off(tail_start);
+        self.last_serialized.clear();
+        write!(
+            &mut self.last_serialized,
+            "{y:04}-{m:02}-{d:02}T{:02}:{:02}:{:02}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )
+        .unwrap();
+        self.last_serialized.extend_from_slice(&tail);
+    }
+}
+
+/// Converts days since the unix epoch into a (year, month, day) date in
+/// the proleptic gregorian calendar. See Howard Hinnant's chrono-compatible
+/// low-level date algorithms.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + (m <= 2) as i64;
+    (y, m, d)
+}
+
+// number of buffers io_uring keeps in flight
+const URING_DEPTH: usize = 8;
+
+/// Pads the very last, non-aligned write with zeros for O_DIRECT and
+/// returns the length to write.
+fn pad_last_write(buf: &mut Buf, padn: &mut usize) -> usize {
+    let mut buf_len = buf.position() as usize;
+    let buf = buf.get_mut();
+    if buf_len % ALIGN != 0 {
+        // this happens on the very last write
+        assert_eq!(
+            *padn, 0,
+            "non-aligned write is only expected once at the very end"
+        );
+        *padn = ALIGN - buf_len % ALIGN;
+        assert!(
+            buf_len + *padn <= buf.len(),
+            "buf will resize, which will destroy alignment guarantees"
+        );
+        buf[buf_len..buf_len + *padn].fill(0_u8);
+        buf_len += *padn;
+    }
+    buf_len
+}
+
+/// Feeds the data in `buf` to `sum`, if any, before it gets padded.
+#[inline]
+fn checksum(sum: &mut Option<Checksummer>, buf: &Buf) {
+    if let Some(sum) = sum {
+        sum.update(&buf.get_ref()[..buf.position() as usize]);
+    }
+}
+
+/// Writes `sums` to the sidecar of `path`, replacing it atomically like
+/// `OutputFile::new_atomic` does.
+fn write_checksums(sums: &Checksums, path: &Path) -> io::Result<()> {
+    let sidecar = Checksums::sidecar_path(path);
+    let mut tmp_name = sidecar.as_os_str().to_owned();
+    tmp_name.push(format!(".tmp.{}", std::process::id()));
+    let tmp = TempFile::new(tmp_name.into());
+
+    let mut text = Vec::new();
+    sums.write_to(&mut text)?;
+    let mut file = fs::File::create(tmp.path())?;
+    file.write_all(&text)?;
+    file.sync_all()?;
+    fs::rename(tmp.path(), &sidecar)?;
+    tmp.keep();
+    Ok(())
+}
+
+/// Writes one buffer at a time, in the order they come in. Returns the
+/// number of bytes written and how many of them are padding.
+fn write_blocking(
+    inner: &impl FileIo,
+    recv: mpsc::Receiver<Buf>,
+    buf_pool: &mpsc::Sender<Buf>,
+    mode: WriteMode,
+    sum: &mut Option<Checksummer>,
+    write_time: &mut Duration,
+) -> io::Result<(usize, usize)> {
+    let mut off = 0_usize;
+    let mut padn = 0_usize;
+    let mut wb = Writeback::default();
+    for mut buf in recv {
+        checksum(sum, &buf);
+        let buf_len = pad_last_write(&mut buf, &mut padn);
+        // bail out on errors, which makes the next send fail
+        let started = Instant::now();
+        write_all_at(inner, &buf.get_ref()[..buf_len], off as u64)?;
+        *write_time += started.elapsed();
+        off += buf_len;
+        wb.written(inner, mode, off)?;
+
+        buf.set_position(0);
+        if let Err(err) = buf_pool.send(buf) {
+            eprintln!("failed to send buf back in pool: {err}");
+        }
+    }
+    Ok((off, padn))
+}
+
+/// Writes buffers through io_uring, with up to a fixed number of them in
+/// flight at increasing offsets. Buffers that were registered upfront
+/// use fixed writes, any extra ones allocated by the pool plain writes.
+struct UringWriter {
+    ring: Ring,
+    // addresses of the registered buffers, by index
+    registered: Vec<usize>,
+}
+
+struct InFlight {
+    buf: Buf,
+    off: usize,
+    len: usize,
+    done: usize,
+}
+
+impl InFlight {
+    /// Counts `n` more bytes as written and returns whether the rest,
+    /// from `done` on, still has to be written. O_DIRECT writes have to
+    /// start at aligned offsets, so after a short one the rest starts at
+    /// the last aligned offset, writing some bytes a second time.
+    fn advance(&mut self, n: usize, mode: WriteMode) -> bool {
+        self.done += n;
+        if self.done >= self.len {
+            return false;
+        }
+        if mode == WriteMode::Direct {
+            self.done -= self.done % ALIGN;
+        }
+        true
+    }
+}
+
+impl UringWriter {
+    /// Also returns the buffers that got registered.
+    fn new(depth: usize) -> io::Result<(Self, Vec<Buf>)> {
+        let ring = Ring::new(depth as u32)?;
+        let buffers: Vec<_> = (0..depth).map(|_| new_buf()).collect();
+        let iovecs: Vec<_> = buffers
+            .iter()
+            .map(|buf| libc::iovec {
+                iov_base: buf.get_ref().as_ptr() as *mut libc::c_void,
+                iov_len: buf.get_ref().len(),
+            })
+            .collect();
+        // the buffers stay alive until the worker, which owns the ring,
+        // exited, see OutputFile::close
+        unsafe { ring.register_buffers(&iovecs)? };
+        let writer = Self {
+            ring,
+            registered: iovecs.iter().map(|iov| iov.iov_base as usize).collect(),
+        };
+        Ok((writer, buffers))
+    }
+
+    fn submit(&mut self, inner: &fs::File, slot: usize, w: &InFlight) {
+        let base = w.buf.get_ref().as_ptr();
+        let buf_index = self
+            .registered
+            .iter()
+            .position(|&addr| addr == base as usize)
+            .map(|i| i as u16);
+        let queued = unsafe {
+            self.ring.push_write(
+                inner,
+                base.add(w.done),
+                w.len - w.done,
+                (w.off + w.done) as u64,
+                buf_index,
+                slot as u64,
+            )
+        };
+        assert!(queued, "there is a ring entry for every slot");
+    }
+
+    fn write_all(
+        mut self,
+        inner: &fs::File,
+        recv: mpsc::Receiver<Buf>,
+        buf_pool: &mpsc::Sender<Buf>,
+        mode: WriteMode,
+        sum: &mut Option<Checksummer>,
+        write_time: &mut Duration,
+    ) -> io::Result<(usize, usize)> {
+        let mut slots: Vec<Option<InFlight>> = self.registered.iter().map(|_| None).collect();
+        let mut in_flight = 0;
+        let mut off = 0_usize;
+        let mut padn = 0_usize;
+        let mut wb = Writeback::default();
+        let mut closed = false;
+        // the first error, after which in flight writes still have to
+        // complete before their buffers can go away
+        let mut err = None;
+
+        loop {
+            // queue up new buffers while there is room, only blocking
+            // when nothing else is going on
+            while !closed && in_flight < slots.len() {
+                let next = match in_flight {
+                    0 => recv.recv().ok(),
+                    _ => match recv.try_recv() {
+                        Ok(buf) => Some(buf),
+                        Err(mpsc::TryRecvError::Empty) => break,
+                        Err(mpsc::TryRecvError::Disconnected) => None,
+                    },
+                };
+                let Some(mut buf) = next else {
+                    closed = true;
+                    break;
+                };
+                checksum(sum, &buf);
+                let len = pad_last_write(&mut buf, &mut padn);
+                let slot = slots.iter().position(Option::is_none).unwrap();
+                let w = InFlight {
+                    buf,
+                    off,
+                    len,
+                    done: 0,
+                };
+                self.submit(inner, slot, &w);
+                slots[slot] = Some(w);
+                in_flight += 1;
+                off += len;
+                if let Err(e) = wb.written(inner, mode, off) {
+                    err.get_or_insert(e);
+                    closed = true;
+                }
+            }
+            if in_flight == 0 {
+                break;
+            }
+
+            let started = Instant::now();
+            let waited = self.ring.submit_and_wait(1);
+            *write_time += started.elapsed();
+            if let Err(e) = waited {
+                // the kernel might still be using the buffers
+                mem::forget(slots);
+                return Err(e);
+            }
+            while let Some((slot, res)) = self.ring.pop_completion() {
+                let slot = slot as usize;
+                let w = slots[slot].as_mut().unwrap();
+                match res {
+                    res if res < 0 => {
+                        err.get_or_insert(io::Error::from_raw_os_error(-res));
+                        closed = true;
+                    }
+                    0 => {
+                        err.get_or_insert(io::Error::from(ErrorKind::WriteZero));
+                        closed = true;
+                    }
+                    res => {
+                        if w.advance(res as usize, mode) {
+                            // short write, queue the rest
+                            let w = slots[slot].take().unwrap();
+                            self.submit(inner, slot, &w);
+                            slots[slot] = Some(w);
+                            continue;
+                        }
+                    }
+                }
+
+                let mut w = slots[slot].take().unwrap();
+                in_flight -= 1;
+                w.buf.set_position(0);
+                if let Err(err) = buf_pool.send(w.buf) {
+                    eprintln!("failed to send buf back in pool: {err}");
+                }
+            }
+        }
+
+        match err {
+            Some(err) => Err(err),
+            None => Ok((off, padn)),
+        }
+    }
+}
+
+/// Keeps track of `WriteMode::Writeback` windows.
+#[derive(Default)]
+struct Writeback {
+    // start of the window that has not been written back yet, and of
+    // the one before it
+    start: usize,
+    prev: usize,
+}
+
+impl Writeback {
+    fn written(&mut self, inner: &impl FileIo, mode: WriteMode, off: usize) -> io::Result<()> {
+        if let WriteMode::Writeback(mib) = mode {
+            if off - self.start >= (mib as usize) << 20 {
+                if let Some(file) = inner.as_file() {
+                    write_back(file, self.prev, self.start, off)?;
+                }
+                self.prev = self.start;
+                self.start = off;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Starts writeback of `start..end` and waits for `prev..start`, which
+/// was started the time before.
+fn write_back(file: &fs::File, prev: usize, start: usize, end: usize) -> io::Result<()> {
+    let sync_file_range = |off: usize, n: usize, flags| match unsafe {
+        libc::sync_file_range(file.as_raw_fd(), off as i64, n as i64, flags)
+    } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    };
+    sync_file_range(start, end - start, libc::SYNC_FILE_RANGE_WRITE)?;
+    if prev < start {
+        sync_file_range(
+            prev,
+            start - prev,
+            libc::SYNC_FILE_RANGE_WAIT_BEFORE
+                | libc::SYNC_FILE_RANGE_WRITE
+                | libc::SYNC_FILE_RANGE_WAIT_AFTER,
+        )?;
+    }
+    Ok(())
+}
+
 type Buf = Cursor<Box<[u8]>>;
 
 fn new_buf() -> Buf {
@@ -258,4 +1065,230 @@ mod tests {
         fmt.serialized_bytes(2671669400006_u64);
         assert_eq!(fmt.last_serialized, expected);
     }
+
+    #[test]
+    fn test_atomic_publish() {
+        let path = crate::test_path("atomic.txt");
+        let path = path.to_str().unwrap();
+        fs::write(path, "previous result\n").unwrap();
+
+        let mut output = OutputFile::new_atomic(path, 1 << 12).unwrap();
+        output.write_bytes(b"1671670171236\n").unwrap();
+        let (tmp, _) = output.publish.as_ref().unwrap();
+        let tmp = tmp.path().to_path_buf();
+        assert!(tmp.exists());
+        // e.g. a failed merge
+        drop(output);
+        assert!(!tmp.exists());
+        assert_eq!("previous result\n", fs::read_to_string(path).unwrap());
+
+        let mut output = OutputFile::new_atomic(path, 1 << 12).unwrap();
+        output.write_bytes(b"1671670171236\n").unwrap();
+        output.finish().unwrap();
+        assert!(!tmp.exists());
+        assert_eq!("1671670171236\n", fs::read_to_string(path).unwrap());
+    }
+
+    #[test]
+    fn test_publish_sync() {
+        use crate::iodirect::file_io::MemFile;
+
+        for (mode, syncs) in [
+            (WriteMode::Buffered, 0),
+            (WriteMode::BufferedSync, 1),
+            (WriteMode::Writeback(1), 0),
+        ] {
+            let file = MemFile::default();
+            let opts = OutputOptions {
+                mode,
+                ..Default::default()
+            };
+            let mut output = OutputFile::start(file.clone(), opts, true);
+            output.write_bytes(b"1671670171236\n").unwrap();
+            output.finish().unwrap();
+            assert_eq!(syncs, file.syncs(), "{mode:?}");
+            assert_eq!(syncs > 0, mode.syncs_publish(), "{mode:?}");
+        }
+        assert!(WriteMode::Direct.syncs_publish());
+    }
+
+    #[test]
+    fn test_discard() {
+        let path = crate::test_path("discard.txt");
+        let path = path.to_str().unwrap();
+
+        // preallocated way past what gets written
+        let mut output = OutputFile::new(path, 1 << 20).unwrap();
+        output.write_bytes(b"1671670171236\n").unwrap();
+        output.discard().unwrap();
+        assert!(!Path::new(path).exists());
+
+        fs::write(path, "previous result\n").unwrap();
+        let mut output = OutputFile::new_atomic(path, 1 << 20).unwrap();
+        output.write_bytes(b"1671670171236\n").unwrap();
+        output.discard().unwrap();
+        assert_eq!("previous result\n", fs::read_to_string(path).unwrap());
+    }
+
+    #[test]
+    fn test_write_modes() {
+        let path = crate::test_path("modes.txt");
+        let path = path.to_str().unwrap();
+        // a bit more than 3 buffers, so that writeback kicks in and the
+        // last write needs padding
+        let lines: Vec<_> = (0..250_000_u64)
+            .map(|i| format!("{}\n", 1671670171236 + i))
+            .collect();
+
+        for mode in ["buffered", "sync", "direct", "writeback:1"] {
+            for io_uring in [false, true] {
+                let opts = OutputOptions {
+                    mode: mode.parse().unwrap(),
+                    io_uring,
+                    checksum: true,
+                };
+                let mut output = OutputFile::with_options(path, 1 << 12, opts).unwrap();
+                for line in &lines {
+                    output.write_bytes(line.as_bytes()).unwrap();
+                }
+                output.finish().unwrap();
+                assert_eq!(
+                    lines.concat(),
+                    fs::read_to_string(path).unwrap(),
+                    "{opts:?}"
+                );
+                // checksums leave out the padding of direct writes
+                let sidecar = Checksums::sidecar_path(Path::new(path));
+                assert_eq!(
+                    Checksums::of_file(Path::new(path)).unwrap(),
+                    Checksums::parse(&fs::read_to_string(sidecar).unwrap()).unwrap(),
+                    "{opts:?}"
+                );
+            }
+        }
+
+        assert!("writeback:0".parse::<WriteMode>().is_err());
+        assert!("direct:1".parse::<WriteMode>().is_err());
+    }
+
+    #[test]
+    fn test_faulty_writes() {
+        use crate::iodirect::file_io::{Fault, MemFile};
+
+        // a few buffers and an unaligned last write
+        let lines: Vec<_> = (0..250_000_u64)
+            .map(|i| format!("{}\n", 1671670171236 + i))
+            .collect();
+        let write = |file: MemFile, mode| {
+            let opts = OutputOptions {
+                mode,
+                ..Default::default()
+            };
+            let mut output = OutputFile::with_file_io(file, opts);
+            for line in &lines {
+                if output.write_bytes(line.as_bytes()).is_err() {
+                    // the worker bailed out, finish says why
+                    break;
+                }
+            }
+            output.finish()
+        };
+
+        for mode in [WriteMode::Buffered, WriteMode::Direct] {
+            let faults = [
+                Fault::Interrupted,
+                Fault::Short(1),
+                Fault::Short(ALIGN - 1),
+                Fault::Interrupted,
+                Fault::Short(CHUNK_SIZE - 1),
+            ];
+            let file = MemFile::default().with_faults(faults);
+            write(file.clone(), mode).unwrap();
+            // the padding of the last direct write is gone again
+            assert_eq!(lines.concat().as_bytes(), file.contents(), "{mode:?}");
+
+            let file = MemFile::default().with_max_io(ALIGN + 3);
+            write(file.clone(), mode).unwrap();
+            assert_eq!(lines.concat().as_bytes(), file.contents(), "{mode:?}");
+
+            for errno in [libc::EIO, libc::ENOSPC] {
+                let file = MemFile::default().with_faults([Fault::Short(100), Fault::Errno(errno)]);
+                let err = write(file, mode).unwrap_err();
+                assert_eq!(Some(errno), err.raw_os_error(), "{mode:?}");
+            }
+            let file = MemFile::default().with_faults([Fault::Short(0)]);
+            let err = write(file, mode).unwrap_err();
+            assert_eq!(ErrorKind::WriteZero, err.kind(), "{mode:?}");
+        }
+
+        // dropping an output that failed does not panic, e.g. during unwinding
+        let file = MemFile::default().with_faults([Fault::Errno(libc::EPIPE)]);
+        let mut output = OutputFile::with_file_io(file, OutputOptions::default());
+        output.write_bytes(lines[0].as_bytes()).unwrap();
+        drop(output);
+    }
+
+    #[test]
+    fn test_uring_short_writes() {
+        let mut w = InFlight {
+            buf: new_buf(),
+            off: 0,
+            len: 3 * ALIGN,
+            done: 0,
+        };
+        assert!(w.advance(ALIGN + 5, WriteMode::Buffered));
+        assert_eq!(ALIGN + 5, w.done);
+        assert!(!w.advance(2 * ALIGN - 5, WriteMode::Buffered));
+
+        // direct writes resume at the last aligned offset
+        w.done = 0;
+        assert!(w.advance(ALIGN + 5, WriteMode::Direct));
+        assert_eq!(ALIGN, w.done);
+        assert!(w.advance(ALIGN - 1, WriteMode::Direct));
+        assert_eq!(ALIGN, w.done);
+        assert!(!w.advance(2 * ALIGN, WriteMode::Direct));
+    }
+
+    #[test]
+    fn test_sequential() {
+        let (reader, writer) = rustix::io::pipe().unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut read = String::new();
+            io::Read::read_to_string(&mut fs::File::from(reader), &mut read).unwrap();
+            read
+        });
+
+        // more than one buffer, which the pipe can not hold at once
+        let lines: Vec<_> = (0..100_000_u64)
+            .map(|i| format!("{}\n", 1671670171236 + i))
+            .collect();
+        let mut output = OutputFile::sequential(fs::File::from(writer), OutputOptions::default());
+        for line in &lines {
+            output.write_bytes(line.as_bytes()).unwrap();
+        }
+        output.finish().unwrap();
+
+        assert_eq!(lines.concat(), reader.join().unwrap());
+    }
+
+    #[test]
+    fn test_iso_formatter() {
+        let mut fmt = IsoFormatter::new(UtcOffset::default());
+        assert_eq!(24, fmt.width());
+        assert_eq!(b"2022-12-22T01:03:25.500Z", fmt.format(1671671005500));
+        assert_eq!(b"2022-12-22T01:03:25.596Z", fmt.format(1671671005596));
+        assert_eq!(b"2022-12-22T01:03:26.007Z", fmt.format(1671671006007));
+        assert_eq!(b"1970-01-01T00:00:00.000Z", fmt.format(0));
+        assert_eq!(b"2000-02-29T23:59:59.999Z", fmt.format(951868799999));
+
+        let mut fmt = IsoFormatter::new("-05:00".parse().unwrap());
+        assert_eq!(29, fmt.width());
+        assert_eq!(b"2022-12-21T20:03:25.500-05:00", fmt.format(1671671005500));
+
+        let mut fmt = IsoFormatter::new("+05:30".parse().unwrap());
+        assert_eq!(b"2022-12-22T06:33:25.500+05:30", fmt.format(1671671005500));
+
+        assert!("05:30".parse::<UtcOffset>().is_err());
+        assert!("+24:00".parse::<UtcOffset>().is_err());
+    }
 }
@@ -0,0 +1,361 @@
+use crate::{
+    iodirect::{self, file_io::FileIo, InputStats, MergeInput, ALIGN, MAX_RECORD_LEN},
+    simd_decimal::{self, PackedKey},
+};
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    time::Instant,
+};
+
+use rustix::fs::OpenOptionsExt;
+
+const READ_SIZE: usize = 1 << 20;
+
+/// Reads sorted `key<TAB>payload` lines where only the key has a fixed
+/// width. Keys get packed the same way as `SortedFile` lines, the
+/// payload is carried along untouched.
+#[derive(Debug)]
+pub struct RecordFile<K: PackedKey = u64, R: FileIo = fs::File> {
+    pub file_size: u64,
+    pub key_digits: usize,
+
+    keys: Vec<K>,
+    // line i spans line_bounds[i]..line_bounds[i + 1] in aligned_buf
+    line_bounds: Vec<u32>,
+    line_pos: usize,
+
+    reader: R,
+    // file offset of the next read
+    read_off: u64,
+    aligned_buf: Box<[u8]>,
+    filled: usize,
+    eof: bool,
+
+    stats: InputStats,
+}
+
+impl<K: PackedKey> RecordFile<K> {
+    /// Opens a file with keys of type `K`. The number of key digits gets
+    /// detected from the first line unless given.
+    pub fn open(file_path: &str, key_digits: Option<usize>) -> io::Result<Self> {
+        let key_digits = match key_digits {
+            Some(key_digits) => key_digits,
+            None => detect_key_digits(file_path)?,
+        };
+        let reader = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(file_path)
+            .map_err(|e| io::Error::new(e.kind(), format!("failed to open {file_path}: {e}")))?;
+        RecordFile::with_reader(reader, key_digits)
+            .map_err(|e| io::Error::new(e.kind(), format!("{file_path}: {e}")))
+    }
+}
+
+impl<K: PackedKey, R: FileIo> RecordFile<K, R> {
+    /// Reads records with `key_digits` wide keys from `reader` instead of
+    /// a file that gets opened by path, see `open`.
+    pub fn with_reader(reader: R, key_digits: usize) -> io::Result<Self> {
+        if key_digits == 0 || key_digits >= K::MAX_LINE_WIDTH {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("unsupported key width: {key_digits}"),
+            ));
+        }
+        let file_size = reader.len()?;
+
+        let aligned_buf = unsafe {
+            // leave MAX_RECORD_LEN bytes in the beginning to deal with
+            // partial records and ALIGN bytes at the end so that keys
+            // can be loaded as full registers and a missing newline can
+            // be added
+            let alloc_size = MAX_RECORD_LEN + READ_SIZE + ALIGN;
+            let layout = std::alloc::Layout::from_size_align(alloc_size, ALIGN).unwrap();
+            let ptr = std::alloc::alloc_zeroed(layout);
+            let slice = std::slice::from_raw_parts_mut(ptr, alloc_size);
+            Box::from_raw(slice)
+        };
+
+        let mut ret = Self {
+            file_size,
+            key_digits,
+
+            keys: Vec::new(),
+            line_bounds: Vec::new(),
+            line_pos: 0,
+
+            reader,
+            read_off: 0,
+            aligned_buf,
+            filled: MAX_RECORD_LEN,
+            eof: false,
+
+            stats: InputStats::default(),
+        };
+        ret.fill_records()?;
+        Ok(ret)
+    }
+
+    #[inline]
+    pub fn peek(&self) -> Option<&K> {
+        self.keys.get(self.line_pos)
+    }
+
+    /// Returns the whole record, including the payload and the newline.
+    #[inline]
+    pub fn peek_bytes(&self) -> Option<&[u8]> {
+        let start = *self.line_bounds.get(self.line_pos)? as usize;
+        let end = *self.line_bounds.get(self.line_pos + 1)? as usize;
+        Some(unsafe { self.aligned_buf.get_unchecked(start..end) })
+    }
+
+    fn fill_records(&mut self) -> io::Result<()> {
+        if self.line_pos < self.keys.len() {
+            return Ok(());
+        }
+
+        // move the partial record, if any, right in front of where the
+        // next read goes
+        let partial_start = self
+            .line_bounds
+            .last()
+            .map_or(MAX_RECORD_LEN, |&end| end as usize);
+        let partial = self.filled - partial_start;
+        if partial > MAX_RECORD_LEN {
+            return Err(record_too_long());
+        }
+        let pos = MAX_RECORD_LEN - partial;
+        self.aligned_buf
+            .copy_within(partial_start..self.filled, pos);
+        self.filled = MAX_RECORD_LEN;
+
+        self.keys.clear();
+        self.line_bounds.clear();
+        self.line_bounds.push(pos as u32);
+        self.line_pos = 0;
+
+        self.fill_buf()?;
+
+        let num_complete_lines = self.line_bounds.len() - 1;
+        self.check_keys(num_complete_lines)?;
+        K::parse_at(
+            &self.aligned_buf,
+            &self.line_bounds[..num_complete_lines],
+            self.key_digits,
+            &mut self.keys,
+        );
+        self.stats.lines += num_complete_lines as u64;
+        self.stats.refills += 1;
+        Ok(())
+    }
+
+    fn fill_buf(&mut self) -> io::Result<()> {
+        // keep reading until there is at least one complete record
+        while self.line_bounds.len() < 2 && !self.eof {
+            let buf = &mut self.aligned_buf[self.filled..MAX_RECORD_LEN + READ_SIZE];
+            if buf.is_empty() {
+                return Err(record_too_long());
+            }
+            let started = Instant::now();
+            let read = self.reader.read_at(buf, self.read_off);
+            self.stats.read_time += started.elapsed();
+            match read {
+                Ok(0) => self.eof = true,
+                Ok(non_zero) => {
+                    self.stats.bytes += non_zero as u64;
+                    self.read_off += non_zero as u64;
+                    simd_decimal::newline_ends(
+                        &buf[..non_zero],
+                        self.filled,
+                        &mut self.line_bounds,
+                    );
+                    self.filled += non_zero;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let last_end = *self.line_bounds.last().unwrap() as usize;
+        if self.eof && self.filled > last_end {
+            // will only happen once if the last line is missing
+            // newline
+            self.aligned_buf[self.filled] = b'\n';
+            self.filled += 1;
+            self.line_bounds.push(self.filled as u32);
+        }
+        Ok(())
+    }
+
+    /// Fails unless each of the first `num_lines` records starts with
+    /// `key_digits` digits followed by a tab, or by the newline if there
+    /// is no payload. The parser would take anything for digits, and a
+    /// shorter key would swallow part of the payload.
+    fn check_keys(&self, num_lines: usize) -> io::Result<()> {
+        let key_digits = self.key_digits;
+        for (i, &start) in self.line_bounds[..num_lines].iter().enumerate() {
+            // past the last record is padding, so this stays in bounds
+            let key = &self.aligned_buf[start as usize..=start as usize + key_digits];
+            if !key[..key_digits].iter().all(u8::is_ascii_digit)
+                || !matches!(key[key_digits], b'\t' | b'\n')
+            {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "line {}: records need a key of {key_digits} digits followed by a tab",
+                        self.stats.lines + i as u64 + 1
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn record_too_long() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("record is longer than {MAX_RECORD_LEN} bytes"),
+    )
+}
+
+impl<K: PackedKey, R: FileIo> MergeInput for RecordFile<K, R> {
+    type Key = K;
+
+    #[inline]
+    fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    #[inline]
+    fn peek(&self) -> Option<&K> {
+        RecordFile::peek(self)
+    }
+
+    #[inline]
+    fn peek_bytes(&self) -> Option<&[u8]> {
+        RecordFile::peek_bytes(self)
+    }
+
+    /// Fails if reading the records after this one does, EINTR aside.
+    #[inline]
+    fn next(&mut self) -> io::Result<()> {
+        if self.line_pos < self.keys.len() {
+            self.line_pos += 1;
+            self.fill_records()?;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> InputStats {
+        self.stats
+    }
+}
+
+/// Returns the number of digits the first line in the file starts with.
+/// Fails for keys that no key type can hold.
+pub fn detect_key_digits(file_path: &str) -> io::Result<usize> {
+    let first_block = iodirect::read_first_block(file_path)?;
+    let digits = first_block
+        .iter()
+        .take_while(|b| b.is_ascii_digit())
+        .count();
+    if digits == 0 || digits >= u128::MAX_LINE_WIDTH {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{file_path}: unsupported key width: {digits}, records need keys of 1 to {} digits",
+                u128::MAX_LINE_WIDTH - 1
+            ),
+        ));
+    }
+    Ok(digits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_across_refills() {
+        let mut expected = Vec::new();
+        let mut contents = String::new();
+        for i in 0..100_000_u64 {
+            let line = format!(
+                "{:013}\tpayload {}\n",
+                1671670171236 + i,
+                "x".repeat(i as usize % 50)
+            );
+            contents += &line;
+            expected.push(line);
+        }
+        // the last line is missing its newline
+        contents.pop();
+        assert!(contents.len() > 2 * READ_SIZE);
+
+        let path = crate::test_path("records.txt");
+        fs::write(&path, &contents).unwrap();
+
+        let mut rf = RecordFile::<u64>::open(path.to_str().unwrap(), None).unwrap();
+        assert_eq!(13, rf.key_digits);
+        for (i, line) in expected.iter().enumerate() {
+            let key = u64::from_str_radix(&line[..13], 16).unwrap();
+            assert_eq!(Some(&key), rf.peek(), "line_idx: #{i}");
+            assert_eq!(Some(line.as_bytes()), rf.peek_bytes(), "line_idx: #{i}");
+            rf.next().unwrap();
+        }
+        assert_eq!(None, rf.peek());
+        assert_eq!(None, rf.peek_bytes());
+    }
+
+    #[test]
+    fn test_faulty_reads() {
+        use crate::iodirect::file_io::{Fault, MemFile};
+
+        let lines: Vec<_> = (0..50_000_u64)
+            .map(|i| format!("{}\t{}\n", 1671670171236 + i, "x".repeat(i as usize % 30)))
+            .collect();
+        let contents = lines.concat();
+
+        let check = |file: MemFile| {
+            let mut rf = RecordFile::<u64, _>::with_reader(file, 13).unwrap();
+            for (i, line) in lines.iter().enumerate() {
+                assert_eq!(Some(line.as_bytes()), rf.peek_bytes(), "line_idx: #{i}");
+                rf.next().unwrap();
+            }
+            assert_eq!(None, rf.peek());
+        };
+        let faults = [
+            Fault::Interrupted,
+            Fault::Short(1),
+            Fault::Short(14),
+            Fault::Interrupted,
+            Fault::Short(ALIGN + 7),
+        ];
+        check(MemFile::new(contents.as_bytes()).with_faults(faults));
+        for max_io in [13, ALIGN + 5] {
+            check(MemFile::new(contents.as_bytes()).with_max_io(max_io));
+        }
+
+        let faults = [Fault::Short(READ_SIZE), Fault::Errno(libc::EIO)];
+        let file = MemFile::new(contents.as_bytes()).with_faults(faults);
+        let mut rf = RecordFile::<u64, _>::with_reader(file, 13).unwrap();
+        let err = lines.iter().find_map(|_| rf.next().err()).unwrap();
+        assert_eq!(Some(libc::EIO), err.raw_os_error());
+
+        // keys that are too short, too long or not numbers at all
+        for bad in [
+            "167167017123\tx\n",
+            "16716701712367\tx\n",
+            "167167017123x\tx\n",
+        ] {
+            let contents = format!("{}{bad}", lines[0]);
+            let err = RecordFile::<u64, _>::with_reader(MemFile::new(contents.as_bytes()), 13)
+                .err()
+                .unwrap();
+            assert_eq!(ErrorKind::InvalidData, err.kind(), "{bad}");
+            assert!(err.to_string().starts_with("line 2:"), "{err}");
+        }
+    }
+}
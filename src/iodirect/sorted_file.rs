@@ -1,36 +1,105 @@
 use crate::{
-    iodirect::{self, ALIGN},
-    simd_decimal, LINE_WIDTH_INCL_NEWLINE,
+    iodirect::{
+        self,
+        file_io::{read_full_at, FileIo},
+        InputStats, MergeInput, ALIGN,
+    },
+    simd_decimal::{PackedKey, PackedParser},
+    LINE_WIDTH_INCL_NEWLINE,
 };
 use std::{
     fs,
-    io::{ErrorKind, Read},
+    io::{self, ErrorKind},
+    time::Instant,
 };
 
-use rustix::fs::{MetadataExt, OpenOptionsExt};
+use rustix::fs::OpenOptionsExt;
 
 #[derive(Debug)]
-pub struct SortedFile {
+pub struct SortedFile<K: PackedKey = u64, R: FileIo = fs::File> {
     pub file_size: u64,
+    pub line_width: usize,
+    parse: PackedParser<K>,
 
-    parsed_lines: Vec<u64>,
+    parsed_lines: Vec<K>,
     parsed_line_pos: usize,
     partial_line_bytes: usize,
 
-    reader: fs::File,
+    reader: R,
+    // file offset of the next forward read
+    read_off: u64,
     aligned_buf: Box<[u8]>,
     pos: usize,
     filled: usize,
+
+    // lines are handed out from the end of the file to the start
+    backwards: bool,
+    // file offset where the next backwards read has to end
+    read_end: u64,
+    // head of the last block read backwards, which is the tail of a line
+    // that starts in the block before it
+    carry: Vec<u8>,
+
+    stats: InputStats,
 }
 
 impl SortedFile {
-    pub fn new(file_path: &str) -> Self {
+    /// Opens a file whose line width gets detected from its first line.
+    pub fn new(file_path: &str) -> io::Result<Self> {
+        Self::open(file_path, None)
+    }
+
+    /// Opens a file whose lines, including the newline, are all
+    /// `line_width` bytes long.
+    pub fn with_line_width(file_path: &str, line_width: usize) -> io::Result<Self> {
+        Self::open(file_path, Some(line_width))
+    }
+}
+
+impl<K: PackedKey> SortedFile<K> {
+    /// Opens a file with keys of type `K`. The line width gets detected
+    /// from the first line unless given.
+    pub fn open(file_path: &str, line_width: Option<usize>) -> io::Result<Self> {
+        Self::open_with(file_path, line_width, false)
+    }
+
+    /// Same as `open`, but lines are handed out starting from the end of
+    /// the file. This turns a descending file into an ascending input and
+    /// vice versa without an intermediate pass over the file.
+    pub fn open_backwards(file_path: &str, line_width: Option<usize>) -> io::Result<Self> {
+        Self::open_with(file_path, line_width, true)
+    }
+
+    fn open_with(file_path: &str, line_width: Option<usize>, backwards: bool) -> io::Result<Self> {
+        let line_width = match line_width {
+            Some(line_width) => line_width,
+            None => detect_line_width(file_path)?,
+        };
         let reader = fs::OpenOptions::new()
             .read(true)
             .custom_flags(libc::O_DIRECT)
             .open(file_path)
-            .expect("failed to open input");
-        let file_size = reader.metadata().unwrap().size();
+            .map_err(|e| io::Error::new(e.kind(), format!("failed to open {file_path}: {e}")))?;
+        SortedFile::with_reader(reader, line_width, backwards)
+            .map_err(|e| io::Error::new(e.kind(), format!("{file_path}: {e}")))
+    }
+}
+
+impl<K: PackedKey, R: FileIo> SortedFile<K, R> {
+    /// Reads lines of `line_width` from `reader` instead of a file that
+    /// gets opened by path, see `open` and `open_backwards`.
+    pub fn with_reader(reader: R, line_width: usize, backwards: bool) -> io::Result<Self> {
+        let parse = K::parser(line_width).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("unsupported line width: {line_width}"),
+            )
+        })?;
+        assert!(
+            line_width <= ALIGN,
+            "align size has to be atleast as big as one line to deal with parsing partial lines"
+        );
+        let file_size = reader.len()?;
 
         let aligned_buf = unsafe {
             const SZ: usize = 1 << 20;
@@ -46,53 +115,72 @@ impl SortedFile {
 
         let mut ret = Self {
             file_size,
+            line_width,
+            parse,
 
             parsed_lines: Vec::new(),
             parsed_line_pos: 0,
             partial_line_bytes: 0,
 
             reader,
+            read_off: 0,
             aligned_buf,
             pos: 0,
             filled: 0,
+
+            backwards,
+            read_end: file_size,
+            carry: Vec::with_capacity(line_width),
+
+            stats: InputStats::default(),
         };
-        ret.fill_parsed_lines();
-        ret
+        ret.fill_parsed_lines()?;
+        Ok(ret)
     }
 
     #[inline]
-    pub fn peek(&self) -> Option<&u64> {
-        self.parsed_lines.get(self.parsed_line_pos)
+    pub fn peek(&self) -> Option<&K> {
+        self.parsed_lines.get(self.line_idx())
     }
 
     #[inline]
-    pub fn next(&mut self) {
-        let start = self.parsed_line_pos;
-        if start < self.parsed_lines.len() {
-            self.parsed_line_pos += 1;
-            self.fill_parsed_lines();
+    pub fn peek_bytes(&self) -> Option<&[u8]> {
+        if self.parsed_line_pos >= self.parsed_lines.len() {
+            return None;
         }
-    }
-
-    #[inline]
-    pub fn peek_bytes(&self) -> Option<&[u8; LINE_WIDTH_INCL_NEWLINE]> {
-        let start = self.pos + self.parsed_line_pos * LINE_WIDTH_INCL_NEWLINE;
-        if start + LINE_WIDTH_INCL_NEWLINE > self.filled {
+        let start = self.pos + self.line_idx() * self.line_width;
+        if start + self.line_width > self.filled {
             return None;
         }
         let bytes = unsafe {
             self.aligned_buf
-                .get_unchecked(start..start + LINE_WIDTH_INCL_NEWLINE)
+                .get_unchecked(start..start + self.line_width)
         };
-        Some(bytes.try_into().unwrap())
+        Some(bytes)
+    }
+
+    /// Index into parsed_lines of the line to hand out next. Out of
+    /// bounds once all parsed lines have been handed out.
+    #[inline]
+    fn line_idx(&self) -> usize {
+        if self.backwards {
+            self.parsed_lines
+                .len()
+                .wrapping_sub(self.parsed_line_pos + 1)
+        } else {
+            self.parsed_line_pos
+        }
     }
 
-    fn fill_parsed_lines(&mut self) {
+    fn fill_parsed_lines(&mut self) -> io::Result<()> {
         if self.parsed_line_pos < self.parsed_lines.len() {
-            return;
+            return Ok(());
         }
 
         self.parsed_lines.clear();
+        if self.backwards {
+            return self.fill_parsed_lines_backwards();
+        }
 
         self.pos = iodirect::ALIGN;
         self.filled = self.pos;
@@ -104,16 +192,18 @@ impl SortedFile {
             self.partial_line_bytes = 0;
         }
 
-        self.fill_buf();
+        self.fill_buf()?;
 
         let buf = &self.aligned_buf[self.pos..self.filled];
 
-        let num_complete_lines = buf.len() / LINE_WIDTH_INCL_NEWLINE;
-        self.partial_line_bytes = buf.len() % LINE_WIDTH_INCL_NEWLINE;
-        simd_decimal::parse_packed_4bit::<6, LINE_WIDTH_INCL_NEWLINE>(
-            &buf[..num_complete_lines * LINE_WIDTH_INCL_NEWLINE],
+        let num_complete_lines = buf.len() / self.line_width;
+        self.partial_line_bytes = buf.len() % self.line_width;
+        (self.parse)(
+            &buf[..num_complete_lines * self.line_width],
             &mut self.parsed_lines,
         );
+        self.stats.lines += num_complete_lines as u64;
+        self.stats.refills += 1;
 
         let n = self.partial_line_bytes;
         // save the partial line at beginning so that we can copy
@@ -121,53 +211,308 @@ impl SortedFile {
         self.aligned_buf
             .copy_within(self.filled - n..self.filled, 0);
         self.filled -= n;
-        assert!((self.filled - self.pos) % LINE_WIDTH_INCL_NEWLINE == 0);
+        assert!((self.filled - self.pos) % self.line_width == 0);
 
         self.parsed_line_pos = 0;
+        Ok(())
     }
 
-    fn fill_buf(&mut self) {
+    fn fill_buf(&mut self) -> io::Result<()> {
         let mut buf = &mut self.aligned_buf[iodirect::ALIGN..];
-        while self.filled - self.pos < LINE_WIDTH_INCL_NEWLINE {
-            match self.reader.read(buf) {
+        while self.filled - self.pos < self.line_width {
+            let started = Instant::now();
+            let read = self.reader.read_at(buf, self.read_off);
+            self.stats.read_time += started.elapsed();
+            match read {
                 Ok(0) => break, // eof
                 Ok(non_zero) => {
+                    self.stats.bytes += non_zero as u64;
                     self.filled += non_zero;
+                    self.read_off += non_zero as u64;
                     buf = &mut buf[non_zero..];
                 }
                 Err(e) if e.kind() == ErrorKind::Interrupted => continue,
-                Err(e) => panic!("fill_parsed_lines: read from file failed: {e})"),
+                Err(e) => return Err(e),
             }
         }
         let avail = self.filled - self.pos;
-        if avail > 0 && avail < LINE_WIDTH_INCL_NEWLINE {
+        if avail > 0 && avail < self.line_width {
             // will only happen once if the last line is missing
             // newline
             self.aligned_buf[self.filled] = b'\n';
             self.filled += 1;
         }
+        Ok(())
+    }
+}
+
+impl<K: PackedKey, R: FileIo> SortedFile<K, R> {
+    /// Reads the block of the file in front of the data that was handed
+    /// out already. Reads stay aligned for O_DIRECT by always ending at
+    /// the start of the previous block, which is aligned after the first
+    /// read.
+    fn fill_parsed_lines_backwards(&mut self) -> io::Result<()> {
+        self.pos = 0;
+        self.filled = 0;
+        self.parsed_line_pos = 0;
+        if self.read_end == 0 {
+            return Ok(());
+        }
+
+        const SZ: u64 = 1 << 20;
+        let first_block = self.read_end == self.file_size;
+        let aligned_end = align_up(self.read_end);
+        let block_start = aligned_end.saturating_sub(SZ);
+
+        let buf = &mut self.aligned_buf[..(aligned_end - block_start) as usize];
+        let started = Instant::now();
+        let read = read_full_at(&self.reader, buf, block_start)?;
+        self.stats.read_time += started.elapsed();
+        self.stats.bytes += read as u64;
+        let off = block_start + read as u64;
+        self.filled = (off.min(self.read_end) - block_start) as usize;
+
+        let w = self.line_width;
+        if first_block && self.file_size % w as u64 != 0 {
+            // the last line is missing its newline
+            self.aligned_buf[self.filled] = b'\n';
+            self.filled += 1;
+        }
+        // what was left over from the block after this one continues
+        // right where this one ends
+        let carry_len = self.carry.len();
+        self.aligned_buf[self.filled..self.filled + carry_len].copy_from_slice(&self.carry);
+        self.filled += carry_len;
+
+        // bytes in front of the first line that starts in this block
+        // belong to a line that starts in the block before it
+        let head = (w - (block_start % w as u64) as usize) % w;
+        let head = head.min(self.filled);
+        self.carry.clear();
+        self.carry.extend_from_slice(&self.aligned_buf[..head]);
+        self.pos = head;
+        if (self.filled - self.pos) % w != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "lines are expected to all have the same width",
+            ));
+        }
+
+        (self.parse)(
+            &self.aligned_buf[self.pos..self.filled],
+            &mut self.parsed_lines,
+        );
+        self.stats.lines += self.parsed_lines.len() as u64;
+        self.stats.refills += 1;
+        self.read_end = block_start;
+
+        if self.parsed_lines.is_empty() {
+            // this block only held the tail of a line, keep going
+            return self.fill_parsed_lines_backwards();
+        }
+        Ok(())
+    }
+}
+
+#[inline]
+fn align_up(off: u64) -> u64 {
+    (off + ALIGN as u64 - 1) / ALIGN as u64 * ALIGN as u64
+}
+
+/// Returns the width of the first line in the file, including the
+/// newline. Empty files get the default width since they have no lines
+/// to parse anyway. Fails for widths that no key type can parse.
+pub fn detect_line_width(file_path: &str) -> io::Result<usize> {
+    let first_block = iodirect::read_first_block(file_path)?;
+    let width = match first_block.iter().position(|&b| b == b'\n') {
+        Some(newline) => newline + 1,
+        // a single line that is missing its newline
+        None if !first_block.is_empty() => first_block.len() + 1,
+        None => LINE_WIDTH_INCL_NEWLINE,
+    };
+    if u128::parser(width).is_none() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{file_path}: unsupported line width: {width}, lines need 1 to {} digits",
+                u128::MAX_LINE_WIDTH - 1
+            ),
+        ));
+    }
+    Ok(width)
+}
+
+impl<K: PackedKey, R: FileIo> MergeInput for SortedFile<K, R> {
+    type Key = K;
+
+    #[inline]
+    fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    #[inline]
+    fn peek(&self) -> Option<&K> {
+        SortedFile::peek(self)
+    }
+
+    #[inline]
+    fn peek_bytes(&self) -> Option<&[u8]> {
+        SortedFile::peek_bytes(self)
+    }
+
+    /// Fails if reading the lines after this one does, EINTR aside.
+    #[inline]
+    fn next(&mut self) -> io::Result<()> {
+        if self.parsed_line_pos < self.parsed_lines.len() {
+            self.parsed_line_pos += 1;
+            self.fill_parsed_lines()?;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> InputStats {
+        self.stats
     }
 }
 
-impl PartialOrd for SortedFile {
+impl<K: PackedKey> PartialOrd for SortedFile<K> {
     #[inline]
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl Ord for SortedFile {
+impl<K: PackedKey> Ord for SortedFile<K> {
     #[inline]
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.peek().cmp(&other.peek())
     }
 }
 
-impl PartialEq for SortedFile {
+impl<K: PackedKey> PartialEq for SortedFile<K> {
     #[inline]
     fn eq(&self, other: &Self) -> bool {
         self.peek() == other.peek()
     }
 }
 
-impl Eq for SortedFile {}
+impl<K: PackedKey> Eq for SortedFile<K> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_line_width() {
+        let path = crate::test_path("width.txt");
+        let path = path.to_str().unwrap();
+        fs::write(path, "1671670171236\n1671670171237\n").unwrap();
+        assert_eq!(14, detect_line_width(path).unwrap());
+
+        fs::write(path, format!("{}\n", "1".repeat(40))).unwrap();
+        let e = detect_line_width(path).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, e.kind());
+        assert!(e.to_string().contains("unsupported line width: 41"), "{e}");
+
+        fs::remove_file(path).unwrap();
+        assert_eq!(
+            ErrorKind::NotFound,
+            detect_line_width(path).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_backwards() {
+        // enough lines to need several blocks, none of which are
+        // multiples of the line width
+        let lines: Vec<_> = (0..200_000_u64)
+            .map(|i| format!("{:013}\n", 1671670171236 + i / 3))
+            .collect();
+        let mut contents = lines.concat();
+        // the last line is missing its newline
+        contents.pop();
+
+        let path = crate::test_path("backwards.txt");
+        fs::write(&path, &contents).unwrap();
+
+        let mut sf = SortedFile::<u64>::open_backwards(path.to_str().unwrap(), None).unwrap();
+        for (i, line) in lines.iter().enumerate().rev() {
+            let key = u64::from_str_radix(&line[..13], 16).unwrap();
+            assert_eq!(Some(&key), sf.peek(), "line_idx: #{i}");
+            assert_eq!(Some(line.as_bytes()), sf.peek_bytes(), "line_idx: #{i}");
+            sf.next().unwrap();
+        }
+        assert_eq!(None, sf.peek());
+        assert_eq!(None, sf.peek_bytes());
+    }
+
+    #[test]
+    fn test_faulty_reads() {
+        use crate::iodirect::file_io::{Fault, MemFile};
+
+        // a bit more than one refill, whose size is not a multiple of
+        // the line width, so lines straddle refills
+        let lines: Vec<_> = (0..100_000_u64)
+            .map(|i| format!("{:013}\n", 1671670171236 + i / 3))
+            .collect();
+        let mut contents = lines.concat();
+        contents.pop();
+
+        let check = |file: MemFile, backwards| {
+            let mut sf = SortedFile::<u64, _>::with_reader(file, 14, backwards).unwrap();
+            let mut order: Vec<_> = lines.iter().enumerate().collect();
+            if backwards {
+                order.reverse();
+            }
+            for (i, line) in order {
+                let key = u64::from_str_radix(&line[..13], 16).unwrap();
+                assert_eq!(Some(&key), sf.peek(), "line_idx: #{i}");
+                assert_eq!(Some(line.as_bytes()), sf.peek_bytes(), "line_idx: #{i}");
+                sf.next().unwrap();
+            }
+            assert_eq!(None, sf.peek());
+        };
+
+        for backwards in [false, true] {
+            let faults = [
+                Fault::Interrupted,
+                Fault::Short(1),
+                Fault::Interrupted,
+                Fault::Short(13),
+                Fault::Short(15),
+                Fault::Short(ALIGN + 7),
+            ];
+            check(
+                MemFile::new(contents.as_bytes()).with_faults(faults),
+                backwards,
+            );
+            for max_io in [13, ALIGN + 5, (1 << 20) - 1] {
+                let file = MemFile::new(contents.as_bytes()).with_max_io(max_io);
+                check(file, backwards);
+            }
+
+            // errors other than EINTR get passed on, whether they hit
+            // the first read or a later one
+            let file = MemFile::new(contents.as_bytes()).with_faults([Fault::Errno(libc::EIO)]);
+            let e = SortedFile::<u64, _>::with_reader(file, 14, backwards).unwrap_err();
+            assert_eq!(Some(libc::EIO), e.raw_os_error());
+
+            // the first refill works, the one after it fails. Backwards,
+            // the first refill ends at the end of the file
+            let mut faults = vec![Fault::Short(1 << 20)];
+            if backwards {
+                faults.push(Fault::Short(0));
+            }
+            faults.push(Fault::Errno(libc::EIO));
+            let file = MemFile::new(contents.as_bytes()).with_faults(faults);
+            let mut sf = SortedFile::<u64, _>::with_reader(file, 14, backwards).unwrap();
+            let e = loop {
+                if let Err(e) = sf.next() {
+                    break e;
+                }
+                assert!(sf.peek().is_some(), "ended without an error");
+            };
+            assert_eq!(Some(libc::EIO), e.raw_os_error());
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::io;
+
+use crate::{
+    aggregate::Bucket,
+    iodirect::{
+        output_file::{OutputFile, OutputOptions, OutputStats},
+        LineSink, CHUNK_SIZE,
+    },
+    simd_decimal::PackedKey,
+};
+
+/// Preallocation for files split by bucket before the size of any bucket
+/// is known.
+pub const FIRST_BUCKET_FILE_SIZE: usize = 64 * CHUNK_SIZE;
+
+/// When `SplitOutput` moves on to the next file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitBy {
+    /// At most this many lines per file, at least 1.
+    Lines(u64),
+    /// At most this many bytes per file, unless a single line is longer.
+    /// At least 1.
+    Bytes(u64),
+    /// One file per time bucket of the epoch millisecond keys.
+    Bucket(Bucket),
+}
+
+/// Spreads the merged lines over several files, each of which is an
+/// `OutputFile` of its own. File names come from a template where `{seq}`
+/// gets replaced by the number of the file, starting at 0, and `{start}`
+/// by the key of the first line in the file, or the start of its bucket.
+/// Files get published like with `OutputFile::new_atomic` once the next
+/// one gets started, the last one by `finish`.
+pub struct SplitOutput {
+    template: String,
+    split_by: SplitBy,
+    // size every new file gets preallocated with
+    expected_file_size: usize,
+    opts: OutputOptions,
+
+    cur: Option<OutputFile>,
+    seq: u64,
+    lines: u64,
+    bytes: u64,
+    bucket_start: u64,
+    // of the files finished so far
+    stats: OutputStats,
+}
+
+impl SplitOutput {
+    /// `expected_file_size` is what each file is expected to grow to. When
+    /// splitting by bucket, later files expect what the one before them
+    /// ended up with instead.
+    pub fn new(template: &str, split_by: SplitBy, expected_file_size: usize) -> Self {
+        assert!(
+            template.contains("{seq}") || template.contains("{start}"),
+            "{template}: split output file names need {{seq}} or {{start}}"
+        );
+        assert!(
+            !matches!(split_by, SplitBy::Lines(0) | SplitBy::Bytes(0)),
+            "split output files need room for at least one line"
+        );
+        Self {
+            template: template.to_string(),
+            split_by,
+            expected_file_size,
+            opts: OutputOptions::default(),
+
+            cur: None,
+            seq: 0,
+            lines: 0,
+            bytes: 0,
+            bucket_start: 0,
+            stats: OutputStats::default(),
+        }
+    }
+
+    pub fn with_options(mut self, opts: OutputOptions) -> Self {
+        self.opts = opts;
+        self
+    }
+
+    /// Number of files started so far.
+    pub fn num_files(&self) -> u64 {
+        self.seq
+    }
+
+    /// Publishes the file that is currently being written, if any.
+    pub fn finish(self) -> io::Result<()> {
+        self.finish_with_stats().map(|_| ())
+    }
+
+    /// Same as `finish`, but also returns what writing all files took.
+    pub fn finish_with_stats(mut self) -> io::Result<OutputStats> {
+        if let Some(cur) = self.cur.take() {
+            self.stats += cur.finish_with_stats()?;
+        }
+        Ok(self.stats)
+    }
+
+    /// Removes the file that is currently being written, if any. Files
+    /// that are complete stay, see `OutputFile::discard`.
+    pub fn discard(mut self) -> io::Result<()> {
+        match self.cur.take() {
+            Some(cur) => cur.discard(),
+            None => Ok(()),
+        }
+    }
+
+    fn roll_over(&mut self, start: u128) -> io::Result<()> {
+        if let Some(cur) = self.cur.take() {
+            self.stats += cur.finish_with_stats()?;
+            if let SplitBy::Bucket(_) = self.split_by {
+                // the rate of lines tends to be steady from one bucket to
+                // the next
+                self.expected_file_size = self.bytes as usize;
+            }
+        }
+
+        let path = self
+            .template
+            .replace("{seq}", &format!("{:04}", self.seq))
+            .replace("{start}", &start.to_string());
+        self.cur = Some(OutputFile::new_atomic_with(
+            &path,
+            self.expected_file_size,
+            self.opts,
+        )?);
+        self.seq += 1;
+        self.lines = 0;
+        self.bytes = 0;
+        Ok(())
+    }
+}
+
+impl LineSink for SplitOutput {
+    #[inline]
+    fn start_line<K: PackedKey>(&mut self, key: K, len: usize) -> io::Result<&mut OutputFile> {
+        let full = match self.split_by {
+            SplitBy::Lines(n) => self.lines >= n,
+            SplitBy::Bytes(n) => self.lines > 0 && self.bytes + len as u64 > n,
+            SplitBy::Bucket(bucket) => {
+                let start = bucket.start_of(key.millis("--split-bucket")?);
+                let full = start != self.bucket_start;
+                self.bucket_start = start;
+                full
+            }
+        };
+        if full || self.cur.is_none() {
+            let start = match self.split_by {
+                SplitBy::Bucket(_) => self.bucket_start as u128,
+                _ => key.to_u128(),
+            };
+            self.roll_over(start)?;
+        }
+
+        self.lines += 1;
+        self.bytes += len as u64;
+        Ok(self.cur.as_mut().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_split_output() {
+        let lines: Vec<_> = [
+            1671670171236_u64,
+            1671670171237,
+            1671670171238,
+            1671673771236,
+            1671673771237,
+        ]
+        .iter()
+        .map(|k| {
+            (
+                u64::from_str_radix(&k.to_string(), 16).unwrap(),
+                format!("{k}\n"),
+            )
+        })
+        .collect();
+
+        let split = |split_by, template: &str| {
+            let template = crate::test_path(template);
+            let mut out = SplitOutput::new(template.to_str().unwrap(), split_by, 1 << 12);
+            for (key, line) in &lines {
+                out.start_line(*key, line.len())
+                    .unwrap()
+                    .write_bytes(line.as_bytes())
+                    .unwrap();
+            }
+            let num_files = out.num_files();
+            out.finish().unwrap();
+            num_files
+        };
+
+        assert_eq!(3, split(SplitBy::Lines(2), "split.lines.{seq}.txt"));
+        assert_eq!(
+            "1671670171238\n1671673771236\n",
+            fs::read_to_string(crate::test_path("split.lines.0001.txt")).unwrap()
+        );
+
+        // 3 lines are exactly 42 bytes
+        assert_eq!(2, split(SplitBy::Bytes(42), "split.bytes.{seq}.txt"));
+        assert_eq!(
+            "1671673771236\n1671673771237\n",
+            fs::read_to_string(crate::test_path("split.bytes.0001.txt")).unwrap()
+        );
+
+        for empty in [SplitBy::Lines(0), SplitBy::Bytes(0)] {
+            let new = || SplitOutput::new("split.empty.{seq}.txt", empty, 0);
+            assert!(std::panic::catch_unwind(new).is_err());
+        }
+
+        let hour = SplitBy::Bucket(Bucket::HOUR);
+        assert_eq!(2, split(hour, "split.hour.{start}.txt"));
+        assert_eq!(
+            "1671670171236\n1671670171237\n1671670171238\n",
+            fs::read_to_string(crate::test_path("split.hour.1671667200000.txt")).unwrap()
+        );
+        assert_eq!(
+            "1671673771236\n1671673771237\n",
+            fs::read_to_string(crate::test_path("split.hour.1671670800000.txt")).unwrap()
+        );
+
+        // keys too wide for a u64 still name files, but have no bucket
+        let wide = 0x99999999999999999999_u128;
+        let template = crate::test_path("split.wide.{start}.txt");
+        let mut out = SplitOutput::new(template.to_str().unwrap(), SplitBy::Lines(1), 0);
+        out.start_line(wide, 21).unwrap();
+        out.finish().unwrap();
+        assert!(crate::test_path("split.wide.99999999999999999999.txt").exists());
+        let template = crate::test_path("split.wide.{seq}.txt");
+        let mut out = SplitOutput::new(template.to_str().unwrap(), hour, 0);
+        let err = out.start_line(wide, 21).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
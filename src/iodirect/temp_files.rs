@@ -0,0 +1,125 @@
+use std::{
+    ffi::CString,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, Ordering},
+        Arc, Once,
+    },
+};
+
+use libc::c_char;
+
+const MAX_TEMP_FILES: usize = 64;
+
+// Paths of temp files that have to be removed when the process gets
+// interrupted. Fixed slots of atomic pointers, so that the signal handler
+// can walk them without locking or allocating.
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: AtomicPtr<c_char> = AtomicPtr::new(ptr::null_mut());
+static TEMP_FILES: [AtomicPtr<c_char>; MAX_TEMP_FILES] = [EMPTY; MAX_TEMP_FILES];
+
+static INSTALL_HANDLER: Once = Once::new();
+
+// Flag of the cancel token that the first signal sets, if any, instead of
+// ending the process.
+static CANCEL_FLAG: AtomicPtr<AtomicBool> = AtomicPtr::new(ptr::null_mut());
+
+/// A file that gets removed when this is dropped or when the process gets
+/// SIGINT or SIGTERM, unless it is kept.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    slot: usize,
+}
+
+impl TempFile {
+    pub fn new(path: PathBuf) -> Self {
+        install_handler();
+
+        let c_path = CString::new(path.as_os_str().as_bytes()).expect("path contains a nul byte");
+        let c_path = c_path.into_raw();
+        let slot = TEMP_FILES
+            .iter()
+            .position(|slot| {
+                slot.compare_exchange(ptr::null_mut(), c_path, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+            })
+            .unwrap_or_else(|| panic!("more than {MAX_TEMP_FILES} temp files at once"));
+        Self { path, slot }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stops tracking the file, which is expected to have been renamed
+    /// already.
+    pub fn keep(mut self) {
+        self.untrack();
+        std::mem::forget(self);
+    }
+
+    fn untrack(&mut self) {
+        let c_path = TEMP_FILES[self.slot].swap(ptr::null_mut(), Ordering::SeqCst);
+        if !c_path.is_null() {
+            // the signal handler did not get to it
+            drop(unsafe { CString::from_raw(c_path) });
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        self.untrack();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn install_handler() {
+    INSTALL_HANDLER.call_once(|| unsafe {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    });
+}
+
+/// Makes the first SIGINT or SIGTERM set `flag` instead of ending the
+/// process. The next one ends it as usual, removing the temp files.
+pub(crate) fn cancel_on_signals(flag: &Arc<AtomicBool>) {
+    // the handler may use the flag at any time, so neither it nor the
+    // one it replaces ever gets freed
+    let flag = Arc::into_raw(flag.clone()) as *mut AtomicBool;
+    CANCEL_FLAG.store(flag, Ordering::SeqCst);
+    install_handler();
+}
+
+/// Undoes `cancel_on_signals` for `flag`, if it is still the one that
+/// signals set.
+pub(crate) fn exit_on_signals(flag: &Arc<AtomicBool>) {
+    let flag = Arc::as_ptr(flag) as *mut AtomicBool;
+    let _ = CANCEL_FLAG.compare_exchange(flag, ptr::null_mut(), Ordering::SeqCst, Ordering::SeqCst);
+}
+
+extern "C" fn on_signal(sig: libc::c_int) {
+    let flag = CANCEL_FLAG.load(Ordering::SeqCst);
+    if !flag.is_null() && !unsafe { &*flag }.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    for slot in &TEMP_FILES {
+        // leaks the path, the process is about to go away anyway
+        let c_path = slot.swap(ptr::null_mut(), Ordering::SeqCst);
+        if !c_path.is_null() {
+            unsafe { libc::unlink(c_path) };
+        }
+    }
+
+    // die from the signal, like without the handler
+    unsafe {
+        libc::signal(sig, libc::SIG_DFL);
+        libc::raise(sig);
+    }
+}
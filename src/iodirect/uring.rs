@@ -0,0 +1,225 @@
+use std::{
+    ffi::c_void,
+    io, mem,
+    os::unix::io::{AsRawFd, RawFd},
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use rustix::{
+    fd::OwnedFd,
+    io_uring::{
+        addr_or_splice_off_in_union, io_uring_cqe, io_uring_enter, io_uring_params,
+        io_uring_register, io_uring_setup, io_uring_sqe, io_uring_user_data, off_or_addr2_union,
+        IoringEnterFlags, IoringFeatureFlags, IoringOp, IoringRegisterOp, IORING_OFF_CQ_RING,
+        IORING_OFF_SQES, IORING_OFF_SQ_RING,
+    },
+    mm::{mmap, munmap, MapFlags, ProtFlags},
+};
+
+/// Just enough of io_uring to keep a few writes in flight: one submission
+/// and one completion ring, shared with the kernel through mmap.
+pub struct Ring {
+    fd: OwnedFd,
+    // (ptr, len) of every mapping, to unmap on drop
+    maps: Vec<(*mut c_void, usize)>,
+
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    sqes: *mut io_uring_sqe,
+    // submitted to the ring, but not to the kernel yet
+    to_submit: u32,
+
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const io_uring_cqe,
+}
+
+// the pointers all point into mappings owned by the ring
+unsafe impl Send for Ring {}
+
+impl Ring {
+    pub fn new(entries: u32) -> io::Result<Self> {
+        let mut p = io_uring_params::default();
+        let fd = io_uring_setup(entries, &mut p)?;
+
+        let sq_size = p.sq_off.array as usize + p.sq_entries as usize * mem::size_of::<u32>();
+        let cq_size =
+            p.cq_off.cqes as usize + p.cq_entries as usize * mem::size_of::<io_uring_cqe>();
+        let single_mmap = p.features.contains(IoringFeatureFlags::SINGLE_MMAP);
+
+        let mut ret = Self {
+            fd,
+            maps: Vec::with_capacity(3),
+            sq_head: ptr::null(),
+            sq_tail: ptr::null(),
+            sq_mask: 0,
+            sq_entries: p.sq_entries,
+            sq_array: ptr::null_mut(),
+            sqes: ptr::null_mut(),
+            to_submit: 0,
+            cq_head: ptr::null(),
+            cq_tail: ptr::null(),
+            cq_mask: 0,
+            cqes: ptr::null(),
+        };
+
+        let sq_len = if single_mmap {
+            sq_size.max(cq_size)
+        } else {
+            sq_size
+        };
+        let sq = ret.map(sq_len, IORING_OFF_SQ_RING)?;
+        let cq = if single_mmap {
+            sq
+        } else {
+            ret.map(cq_size, IORING_OFF_CQ_RING)?
+        };
+        let sqes = ret.map(
+            p.sq_entries as usize * mem::size_of::<io_uring_sqe>(),
+            IORING_OFF_SQES,
+        )?;
+
+        unsafe {
+            let at = |base: *mut c_void, off: u32| base.cast::<u8>().add(off as usize);
+            ret.sq_head = at(sq, p.sq_off.head).cast();
+            ret.sq_tail = at(sq, p.sq_off.tail).cast();
+            ret.sq_mask = *at(sq, p.sq_off.ring_mask).cast::<u32>();
+            ret.sq_array = at(sq, p.sq_off.array).cast();
+            ret.sqes = sqes.cast();
+            ret.cq_head = at(cq, p.cq_off.head).cast();
+            ret.cq_tail = at(cq, p.cq_off.tail).cast();
+            ret.cq_mask = *at(cq, p.cq_off.ring_mask).cast::<u32>();
+            ret.cqes = at(cq, p.cq_off.cqes).cast();
+        }
+        Ok(ret)
+    }
+
+    fn map(&mut self, len: usize, offset: u64) -> io::Result<*mut c_void> {
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED | MapFlags::POPULATE,
+                &self.fd,
+                offset,
+            )?
+        };
+        self.maps.push((ptr, len));
+        Ok(ptr)
+    }
+
+    /// Registers buffers for `write_fixed`, which saves the kernel from
+    /// mapping them for every write.
+    ///
+    /// # Safety
+    ///
+    /// The buffers have to stay alive as long as the ring does.
+    pub unsafe fn register_buffers(&self, bufs: &[libc::iovec]) -> io::Result<()> {
+        io_uring_register(
+            &self.fd,
+            IoringRegisterOp::RegisterBuffers,
+            bufs.as_ptr().cast(),
+            bufs.len() as u32,
+        )?;
+        Ok(())
+    }
+
+    /// Queues a write of `len` bytes at `buf` to `fd` at `off`. `buf_index`
+    /// is the index of the registered buffer `buf` points into, if any.
+    /// Returns false if the submission ring is full.
+    ///
+    /// # Safety
+    ///
+    /// The bytes have to stay alive until the write completes.
+    pub unsafe fn push_write(
+        &mut self,
+        fd: &impl AsRawFd,
+        buf: *const u8,
+        len: usize,
+        off: u64,
+        buf_index: Option<u16>,
+        user_data: u64,
+    ) -> bool {
+        let tail = (*self.sq_tail).load(Ordering::Relaxed);
+        let head = (*self.sq_head).load(Ordering::Acquire);
+        if tail.wrapping_sub(head) >= self.sq_entries {
+            return false;
+        }
+
+        let idx = tail & self.sq_mask;
+        let mut sqe = io_uring_sqe {
+            opcode: IoringOp::Write,
+            fd: fd.as_raw_fd() as RawFd,
+            off_or_addr2: off_or_addr2_union { off },
+            addr_or_splice_off_in: addr_or_splice_off_in_union {
+                addr: (buf as *mut c_void).into(),
+            },
+            len: len as u32,
+            user_data: io_uring_user_data::from_u64(user_data),
+            ..Default::default()
+        };
+        if let Some(buf_index) = buf_index {
+            sqe.opcode = IoringOp::WriteFixed;
+            sqe.buf.buf_index = buf_index;
+        }
+        self.sqes.add(idx as usize).write(sqe);
+        self.sq_array.add(idx as usize).write(idx);
+        (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        self.to_submit += 1;
+        true
+    }
+
+    /// Hands queued writes to the kernel and waits until at least
+    /// `min_complete` of them completed.
+    pub fn submit_and_wait(&mut self, min_complete: u32) -> io::Result<()> {
+        loop {
+            let res = unsafe {
+                io_uring_enter(
+                    &self.fd,
+                    self.to_submit,
+                    min_complete,
+                    IoringEnterFlags::GETEVENTS,
+                    ptr::null(),
+                    0,
+                )
+            };
+            match res {
+                Ok(submitted) => {
+                    self.to_submit -= submitted;
+                    return Ok(());
+                }
+                Err(rustix::io::Errno::INTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Returns the user data and result of the next completed write.
+    pub fn pop_completion(&mut self) -> Option<(u64, i32)> {
+        unsafe {
+            let head = (*self.cq_head).load(Ordering::Relaxed);
+            let tail = (*self.cq_tail).load(Ordering::Acquire);
+            if head == tail {
+                return None;
+            }
+            let cqe = &*self.cqes.add((head & self.cq_mask) as usize);
+            let ret = (cqe.user_data.u64_(), cqe.res);
+            (*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+            Some(ret)
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        for &(ptr, len) in &self.maps {
+            let _ = unsafe { munmap(ptr, len) };
+        }
+    }
+}
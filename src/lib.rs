@@ -0,0 +1,59 @@
+#![feature(array_windows)]
+#![feature(array_chunks)]
+#![feature(iter_array_chunks)]
+#![feature(ptr_sub_ptr)]
+#![feature(maybe_uninit_uninit_array)]
+#![feature(maybe_uninit_array_assume_init)]
+#![feature(portable_simd)]
+#![feature(stdsimd_internal)]
+#![feature(stdsimd)]
+
+pub mod aggregate;
+pub mod cancel;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzzing;
+pub mod generate;
+pub mod iodirect;
+pub mod merge;
+pub mod progress;
+pub mod query;
+pub mod report;
+pub mod simd_decimal;
+pub mod summary;
+pub mod verify;
+
+use iodirect::{ALIGN, LINE_WIDTH_INCL_NEWLINE};
+use simd_decimal::MAX_WIDE_LINE_WIDTH;
+
+// Flagged as dead code unfortunately
+#[allow(dead_code)]
+const fn check_consts() {
+    assert!(
+        ALIGN >= MAX_WIDE_LINE_WIDTH && MAX_WIDE_LINE_WIDTH >= LINE_WIDTH_INCL_NEWLINE,
+        "align size has to be atleast as big as one line to deal with parsing partial lines"
+    )
+}
+
+const _: () = check_consts();
+
+/// Returns the path of a file named `name` for a test to write. Every
+/// test process gets a directory of its own, so that test runs side by
+/// side do not write over each other's files, which gets removed when
+/// the process exits.
+#[cfg(test)]
+pub(crate) fn test_path(name: &str) -> std::path::PathBuf {
+    fn dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("mpchal4-{}", std::process::id()))
+    }
+    extern "C" fn remove_dir() {
+        let _ = std::fs::remove_dir_all(dir());
+    }
+
+    static CREATED: std::sync::Once = std::sync::Once::new();
+    CREATED.call_once(|| {
+        std::fs::create_dir_all(dir()).unwrap();
+        // the test harness has no teardown to remove it
+        unsafe { libc::atexit(remove_dir) };
+    });
+    dir().join(name)
+}
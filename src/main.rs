@@ -1,18 +1,29 @@
-#![feature(array_windows)]
-#![feature(array_chunks)]
-#![feature(iter_array_chunks)]
-#![feature(ptr_sub_ptr)]
-#![feature(maybe_uninit_uninit_array)]
-#![feature(maybe_uninit_array_assume_init)]
-#![feature(portable_simd)]
-#![feature(stdsimd_internal)]
-#![feature(stdsimd)]
-use std::{env, io};
-
-use iodirect::{output_file::OutputFile, sorted_file::SortedFile, ALIGN, LINE_WIDTH_INCL_NEWLINE};
-
-mod iodirect;
-mod simd_decimal;
+use std::{
+    env, fs, io, panic, process,
+    time::{Duration, Instant},
+};
+
+use cli::{Args, Command, EXIT_CANCELLED, EXIT_ERROR, EXIT_FAILED, EXIT_USAGE};
+use mpchal4::{
+    aggregate::Aggregator,
+    cancel::{self, CancelToken},
+    iodirect::{
+        key_index::KeyIndexWriter,
+        output_file::{IsoFormatter, OutputFile, OutputStats},
+        record_file::{detect_key_digits, RecordFile},
+        sorted_file::{detect_line_width, SortedFile},
+        split_output::{SplitBy, SplitOutput, FIRST_BUCKET_FILE_SIZE},
+        MergeInput,
+    },
+    merge::SortingWriter,
+    progress::ProgressReporter,
+    query,
+    report::Report,
+    simd_decimal::PackedKey,
+    summary, verify,
+};
+
+mod cli;
 
 // The main strategies involved in this solution are:
 //
@@ -55,91 +66,385 @@ mod simd_decimal;
 // - since "12" appears in the most significant positions in the original number, we need to
 //   reverse the order in the byte array so that when comparing numbers, "1234" will be smaller
 //   than "1235": [0x34, 0x12]
+// - numbers are right aligned before packing, so the result is plain BCD and "42" compares
+//   smaller than "0100" even when the inputs have different line widths
 //
 //   Similar to normal parsing of ascii numbers to binary numbers, this 4bit packed parsing can
 //   also be done via SIMD instructions. See: do_parse_packed_4bit in the simd_decimal module to
 //   see the full implementation.
 fn main() {
-    let mut input_files: Vec<_> = env::args()
-        .skip(1)
-        .map(|input_file| SortedFile::new(&input_file))
-        .collect();
+    let command = match Command::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("mpchal4: {e}\nsee `mpchal4 help` for usage");
+            process::exit(EXIT_USAGE);
+        }
+    };
+    // bugs are panics, the default hook has printed them by the time
+    // they get here
+    let status =
+        panic::catch_unwind(move || run(command).unwrap_or_else(failed)).unwrap_or(EXIT_ERROR);
+    process::exit(status);
+}
 
-    // provide default inputs to make running profiler easier
-    if input_files.is_empty() {
-        for pat in ["2", "4", "8", "10", "20", "40"] {
-            let path = format!("files/{pat}m.txt");
-            input_files.push(SortedFile::new(&path));
+/// Runs `command` and returns the exit status.
+fn run(command: Command) -> io::Result<i32> {
+    match command {
+        Command::Help(usage) => println!("{usage}"),
+        Command::Merge(args) => {
+            if !merge_inputs(&args)? {
+                return Ok(EXIT_CANCELLED);
+            }
+        }
+        Command::Verify {
+            path,
+            inputs,
+            records,
+        } => {
+            if !verify::run(&path, &inputs, records)? {
+                return Ok(EXIT_FAILED);
+            }
+        }
+        Command::Generate { path, generator } => generator
+            .write(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("failed to write {path}: {e}")))?,
+        Command::Stats { paths, records } => summary::run(&paths, records)?,
+        Command::Query { path, from, to } => {
+            if query::run(&path, from, to)? == 0 {
+                return Ok(EXIT_FAILED);
+            }
         }
     }
+    Ok(0)
+}
+
+/// Reports an error that is no bug, like an input that can not be read.
+fn failed(e: io::Error) -> i32 {
+    eprintln!("mpchal4: {e}");
+    EXIT_ERROR
+}
+
+/// Returns false if the merge got cancelled by a signal, in which case
+/// the partial output is gone.
+fn merge_inputs(args: &Args) -> io::Result<bool> {
+    let started = Instant::now();
+    // keys with more than 16 digits don't fit in a u64 when packed, only
+    // pay for u128 comparisons when some input actually needs it
+    let completed = if args.records {
+        let key_digits: Vec<_> = args
+            .input_paths
+            .iter()
+            .map(|path| detect_key_digits(path))
+            .collect::<io::Result<_>>()?;
+        if key_digits.iter().any(|&d| d >= u64::MAX_LINE_WIDTH) {
+            merge_records::<u128>(args, &key_digits, started)?
+        } else {
+            merge_records::<u64>(args, &key_digits, started)?
+        }
+    } else {
+        let line_widths: Vec<_> = args
+            .input_paths
+            .iter()
+            .map(|path| detect_line_width(path))
+            .collect::<io::Result<_>>()?;
+        if line_widths.iter().any(|&w| w > u64::MAX_LINE_WIDTH) {
+            merge_lines::<u128>(args, &line_widths, started)?
+        } else {
+            merge_lines::<u64>(args, &line_widths, started)?
+        }
+    };
+    Ok(completed)
+}
 
+fn merge_lines<K: PackedKey>(
+    args: &Args,
+    line_widths: &[usize],
+    started: Instant,
+) -> io::Result<bool> {
+    let input_files = args
+        .input_paths
+        .iter()
+        .zip(line_widths)
+        .map(|(path, &width)| {
+            if args.read_backwards(path)? {
+                SortedFile::<K>::open_backwards(path, Some(width))
+            } else {
+                SortedFile::<K>::open(path, Some(width))
+            }
+        })
+        .collect::<io::Result<_>>()?;
+    let min_width = line_widths.iter().copied().min().unwrap_or(1);
+    merge(args, input_files, min_width, started)
+}
+
+fn merge_records<K: PackedKey>(
+    args: &Args,
+    key_digits: &[usize],
+    started: Instant,
+) -> io::Result<bool> {
+    let input_files = args
+        .input_paths
+        .iter()
+        .zip(key_digits)
+        .map(|(path, &digits)| {
+            if args.read_backwards(path)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{path}: record mode needs inputs sorted in the output order"),
+                ));
+            }
+            RecordFile::<K>::open(path, Some(digits))
+        })
+        .collect::<io::Result<_>>()?;
+    // a record is at least a key and a newline
+    let min_width = key_digits.iter().copied().min().unwrap_or(0) + 1;
+    merge(args, input_files, min_width, started)
+}
+
+fn merge<I: MergeInput>(
+    args: &Args,
+    input_files: Vec<I>,
+    min_line_width: usize,
+    started: Instant,
+) -> io::Result<bool> {
     let mut expected_file_size = 0;
     for file in &input_files {
-        expected_file_size += file.file_size;
+        expected_file_size += file.file_size();
     }
 
-    let mut output = OutputFile::new("result.txt", expected_file_size as usize);
-    let mut wr = SortingWriter::new(input_files);
-    wr.write_to(&mut output).unwrap();
-}
+    let max_lines = expected_file_size / min_line_width as u64;
 
-struct SortingWriter(Vec<SortedFile>);
+    // the first signal stops the merge, the outputs get removed below
+    let cancel = CancelToken::new();
+    cancel.cancel_on_signals();
+    let mut wr = SortingWriter::new(input_files)
+        .with_tie_break(args.tie_break)
+        .with_order(args.output_order)
+        .with_cancel(cancel.clone());
+    if args.progress {
+        wr = wr.with_progress(ProgressReporter::stderr(Duration::from_secs(1)));
+    }
+    if let Some(bucket) = args.aggregate {
+        // the number of buckets is not known upfront
+        let mut output = args.open_output(0)?;
+        let mut agg = Aggregator::new(bucket).with_gaps(args.gaps);
+        let opened = Instant::now();
+        if !completed(wr.aggregate_to(&mut agg, &mut output))? {
+            output.discard()?;
+            return Ok(false);
+        }
+        let merged = Instant::now();
+        if !completed(stop_cancelling(&cancel))? {
+            output.discard()?;
+            return Ok(false);
+        }
+        let output_stats = output.finish_with_stats()?;
+        wr.finish()?;
+        write_report(args, &wr, output_stats, [started, opened, merged])?;
+        return Ok(true);
+    }
+    if let Some(tags) = args.tags() {
+        // every line grows by a tab and the tag
+        let longest_tag = tags.iter().map(Vec::len).max().unwrap_or(0);
+        expected_file_size += max_lines * (longest_tag as u64 + 1);
+        wr = wr.with_tags(tags);
+    }
+    if let Some(offset) = args.iso {
+        // keys get replaced, so this over-estimates a bit
+        let iso = IsoFormatter::new(offset);
+        expected_file_size += max_lines * iso.width() as u64;
+        wr = wr.with_iso_timestamps(iso);
+    }
+    if args.tag_sidecar {
+        let output_path = args.sidecar_base();
+        let sidecar_path = format!("{output_path}.src");
+        let sidecar =
+            OutputFile::new_atomic_with(&sidecar_path, max_lines as usize, args.sidecar_opts())?;
+        wr = wr.with_sidecar(sidecar);
+    }
+    if let Some(spacing) = args.index {
+        let output_path = args.sidecar_base();
+        let index_path = format!("{output_path}.idx");
+        let out = OutputFile::new_atomic_with(&index_path, 0, args.sidecar_opts())?;
+        let index = KeyIndexWriter::new(out, spacing, args.output_order)?;
+        wr = wr.with_index(index);
+    }
 
-impl SortingWriter {
-    fn new(sfs: Vec<SortedFile>) -> Self {
-        Self(sfs)
+    if let Some(split_by) = args.split {
+        let template = args.split_template.as_deref().unwrap_or("result.{seq}.txt");
+        let file_size = match split_by {
+            SplitBy::Lines(n) => n.saturating_mul(expected_file_size / max_lines.max(1)),
+            SplitBy::Bytes(n) => n,
+            SplitBy::Bucket(_) => FIRST_BUCKET_FILE_SIZE as u64,
+        };
+        let file_size = file_size.min(expected_file_size) as usize;
+        let mut output =
+            SplitOutput::new(template, split_by, file_size).with_options(args.output_opts);
+        let opened = Instant::now();
+        if !completed(wr.write_to(&mut output))? {
+            output.discard()?;
+            return Ok(false);
+        }
+        let merged = Instant::now();
+        if !completed(stop_cancelling(&cancel))? {
+            output.discard()?;
+            return Ok(false);
+        }
+        let output_stats = output.finish_with_stats()?;
+        wr.finish()?;
+        write_report(args, &wr, output_stats, [started, opened, merged])?;
+        return Ok(true);
     }
 
-    fn write_to(&mut self, dest: &mut OutputFile) -> io::Result<()> {
-        loop {
-            let Some(min_sf) = self
-                .0
-                .iter_mut()
-                .min_by_key(|sf| *sf.peek().unwrap_or(&u64::MAX)) else { return Ok(()) };
+    let mut output = args.open_output(expected_file_size as usize)?;
+    let opened = Instant::now();
+    if !completed(wr.write_to(&mut output))? {
+        output.discard()?;
+        return Ok(false);
+    }
+    let merged = Instant::now();
+    if !completed(stop_cancelling(&cancel))? {
+        output.discard()?;
+        return Ok(false);
+    }
+    let output_stats = output.finish_with_stats()?;
+    // the sidecar and index only get published next to a complete output
+    wr.finish()?;
+    write_report(args, &wr, output_stats, [started, opened, merged])?;
+    Ok(true)
+}
 
-            match min_sf.peek_bytes() {
-                None => break Ok(()),
-                Some(line) => {
-                    dest.write_bytes(line)?;
-                    min_sf.next();
-                }
-            }
+/// Runs once all lines are merged. A signal from here on ends the process
+/// rather than letting the outputs get published, which removes those
+/// that are not yet. Fails if the merge got cancelled before.
+fn stop_cancelling(cancel: &CancelToken) -> io::Result<()> {
+    cancel.exit_on_signals();
+    cancel.check()
+}
+
+/// Returns false if the merge got cancelled, in which case the partial
+/// output is up to the caller to remove.
+fn completed(merged: io::Result<()>) -> io::Result<bool> {
+    match merged {
+        Ok(()) => Ok(true),
+        Err(e) if cancel::is_cancelled(&e) => {
+            eprintln!("mpchal4: {e}, removing the partial output");
+            Ok(false)
         }
+        Err(e) => Err(io::Error::new(e.kind(), format!("merge failed: {e}"))),
     }
 }
 
+/// Writes the `--report`, if asked for. `phases` are when the merge
+/// started, when everything was opened and when all lines were merged,
+/// the merge is finished by now.
+fn write_report<I: MergeInput>(
+    args: &Args,
+    wr: &SortingWriter<I>,
+    output: OutputStats,
+    phases: [Instant; 3],
+) -> io::Result<()> {
+    let Some(path) = &args.report else {
+        return Ok(());
+    };
+    let [started, opened, merged] = phases;
+    let report = Report {
+        inputs: args
+            .input_paths
+            .iter()
+            .cloned()
+            .zip(wr.input_stats())
+            .collect(),
+        merge: wr.stats(),
+        output,
+        open_time: opened - started,
+        merge_time: merged - opened,
+        finish_time: merged.elapsed(),
+    };
+    let written = match path.as_str() {
+        "-" => report.write_json(&mut io::stderr().lock()),
+        path => fs::File::create(path).and_then(|mut f| report.write_json(&mut f)),
+    };
+    written.map_err(|e| io::Error::new(e.kind(), format!("failed to write report to {path}: {e}")))
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
         fs,
         io::{BufRead, BufReader},
+        sync::Mutex,
     };
 
     use super::*;
+    use mpchal4::{
+        generate::Generator,
+        iodirect::{detect_order, output_file::UtcOffset, Order},
+        merge::TieBreak,
+    };
 
-    const FILE: &str = "files/2m.txt";
+    /// Returns the path of a generated input with `millions` million
+    /// lines, which gets generated once per test run.
+    fn fixture(millions: u64) -> String {
+        static GENERATED: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+        let path = test_path(&format!("{millions}m.txt"));
+        let path = path.to_str().unwrap().to_string();
+        let mut generated = GENERATED.lock().unwrap();
+        if !generated.contains(&millions) {
+            Generator::new(millions * 1_000_000)
+                .with_seed(millions)
+                .with_duplicates(0.05)
+                .write(&path)
+                .unwrap();
+            generated.push(millions);
+        }
+        path
+    }
+
+    /// Same as `test_path` of the library, whose test helpers the
+    /// binary does not get to see: a file in a directory of this test
+    /// process, removed when it exits.
+    pub(crate) fn test_path(name: &str) -> std::path::PathBuf {
+        fn dir() -> std::path::PathBuf {
+            std::env::temp_dir().join(format!("mpchal4-{}", std::process::id()))
+        }
+        extern "C" fn remove_dir() {
+            let _ = fs::remove_dir_all(dir());
+        }
+
+        static CREATED: std::sync::Once = std::sync::Once::new();
+        CREATED.call_once(|| {
+            fs::create_dir_all(dir()).unwrap();
+            // the test harness has no teardown to remove it
+            unsafe { libc::atexit(remove_dir) };
+        });
+        dir().join(name)
+    }
 
     #[test]
     fn test_sorted_file() {
-        let mut sf = SortedFile::new(FILE);
-        assert_eq!(Some(&0x167167017123600), sf.peek());
-        sf.next();
-        assert_eq!(Some(&0x167167017123600), sf.peek());
+        let file = fixture(2);
+        let mut lines = stdlib_solution_iter(&[&file]);
+        let mut sf = SortedFile::new(&file).unwrap();
+        assert_eq!(Some(&0x1671670171236), sf.peek());
+        lines.next();
+        sf.next().unwrap();
+        assert_eq!(Some(&get_4bit_compressed(lines.next().unwrap())), sf.peek());
     }
 
     fn get_4bit_compressed(x: u64) -> u64 {
-        let mut as_str = x.to_string();
-        as_str += "00";
-        u64::from_str_radix(&as_str, 16).unwrap()
+        u64::from_str_radix(&x.to_string(), 16).unwrap()
     }
 
     #[test]
     fn test_whole_file() {
-        let mut lines = stdlib_solution_iter(&[FILE]);
+        let file = fixture(2);
+        let mut lines = stdlib_solution_iter(&[&file]);
 
-        let mut sf = SortedFile::new(FILE);
+        let mut sf = SortedFile::new(&file).unwrap();
         let mut n = 0;
-        let mut peeked_bytes = sf.peek_bytes().cloned();
+        let mut peeked_bytes = sf.peek_bytes().map(|b| b.to_vec());
         while let Some(&actual) = sf.peek() {
             let expected = lines.next().unwrap();
             assert_eq!(get_4bit_compressed(expected), actual, "line_idx: #{n}");
@@ -148,8 +453,8 @@ mod tests {
                 String::from_utf8(peeked_bytes.unwrap().to_vec()),
                 "line_idx: #{n}"
             );
-            sf.next();
-            peeked_bytes = sf.peek_bytes().cloned();
+            sf.next().unwrap();
+            peeked_bytes = sf.peek_bytes().map(|b| b.to_vec());
             n += 1;
         }
         assert_eq!(2_000_000, n);
@@ -157,12 +462,16 @@ mod tests {
 
     #[test]
     fn test_two_files() {
-        let inputs = ["files/2m.txt", "files/4m.txt"];
-        let mut temp_file = std::env::temp_dir();
-        temp_file.push("mpchal4.tmp.txt");
+        let inputs = [fixture(2), fixture(4)];
+        let inputs = [inputs[0].as_str(), inputs[1].as_str()];
+        let temp_file = test_path("two_files.txt");
 
         {
-            let sorted_files: Vec<_> = inputs.iter().copied().map(SortedFile::new).collect();
+            let sorted_files: Vec<_> = inputs
+                .iter()
+                .copied()
+                .map(|path| SortedFile::new(path).unwrap())
+                .collect();
             let expected_file_size: usize =
                 sorted_files.iter().map(|sf| sf.file_size as usize).sum();
             let mut wr = SortingWriter::new(sorted_files);
@@ -171,6 +480,7 @@ mod tests {
                     temp_file.as_path().to_str().unwrap(),
                     expected_file_size as usize,
                 )
+                .unwrap()
             };
             wr.write_to(&mut output).unwrap();
         }
@@ -193,6 +503,311 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_mixed_line_widths() {
+        let inputs = [
+            (
+                test_path("seconds.txt"),
+                "0000000007\n1671670171\n1671670172\n",
+            ),
+            (test_path("millis.txt"), "1671670171000\n1671670171236\n"),
+            (
+                test_path("micros.txt"),
+                "0000000000000042\n1671670171236000\n1671670171236001",
+            ),
+        ];
+        for (path, contents) in &inputs {
+            fs::write(path, contents).unwrap();
+        }
+        let output = test_path("mixed.tmp.txt");
+
+        {
+            let sorted_files: Vec<_> = inputs
+                .iter()
+                .map(|(path, _)| SortedFile::new(path.to_str().unwrap()).unwrap())
+                .collect();
+            let widths: Vec<_> = sorted_files.iter().map(|sf| sf.line_width).collect();
+            assert_eq!(vec![11, 14, 17], widths);
+
+            let mut output = OutputFile::new(output.to_str().unwrap(), 1 << 12).unwrap();
+            SortingWriter::new(sorted_files)
+                .write_to(&mut output)
+                .unwrap();
+        }
+
+        assert_eq!(
+            "0000000007\n\
+             0000000000000042\n\
+             1671670171\n\
+             1671670172\n\
+             1671670171000\n\
+             1671670171236\n\
+             1671670171236000\n\
+             1671670171236001\n",
+            fs::read_to_string(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_wide_keys() {
+        let inputs = [
+            (
+                test_path("nanos.txt"),
+                "1671670171235999999\n1671670171236000001\n",
+            ),
+            (test_path("millis2.txt"), "1671670171236\n1671670171237\n"),
+        ];
+        for (path, contents) in &inputs {
+            fs::write(path, contents).unwrap();
+        }
+        let output = test_path("wide.tmp.txt");
+
+        {
+            let sorted_files: Vec<_> = inputs
+                .iter()
+                .map(|(path, _)| SortedFile::<u128>::open(path.to_str().unwrap(), None).unwrap())
+                .collect();
+            let mut output = OutputFile::new(output.to_str().unwrap(), 1 << 12).unwrap();
+            SortingWriter::new(sorted_files)
+                .write_to(&mut output)
+                .unwrap();
+        }
+
+        assert_eq!(
+            "1671670171236\n\
+             1671670171237\n\
+             1671670171235999999\n\
+             1671670171236000001\n",
+            fs::read_to_string(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_records_stable_ties() {
+        let inputs = [
+            (
+                test_path("records.a.txt"),
+                "1671670171236\ta1\n1671670171237\ta2\n1671670171237\ta3\n",
+            ),
+            (
+                test_path("records.b.txt"),
+                "1671670171235\tb1 longer payload\n1671670171237\tb2\n",
+            ),
+            (
+                test_path("records.c.txt"),
+                "1671670171237\tc1\n1671670171238",
+            ),
+        ];
+        for (path, contents) in &inputs {
+            fs::write(path, contents).unwrap();
+        }
+        let output = test_path("records.tmp.txt");
+
+        {
+            let record_files: Vec<_> = inputs
+                .iter()
+                .map(|(path, _)| RecordFile::<u64>::open(path.to_str().unwrap(), None).unwrap())
+                .collect();
+            let mut output = OutputFile::new(output.to_str().unwrap(), 1 << 12).unwrap();
+            SortingWriter::new(record_files)
+                .write_to(&mut output)
+                .unwrap();
+        }
+
+        assert_eq!(
+            "1671670171235\tb1 longer payload\n\
+             1671670171236\ta1\n\
+             1671670171237\ta2\n\
+             1671670171237\ta3\n\
+             1671670171237\tb2\n\
+             1671670171237\tc1\n\
+             1671670171238\n",
+            fs::read_to_string(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tagged_output() {
+        let inputs = [
+            (test_path("tagged.a.txt"), "1671670171236\n1671670171237\n"),
+            (test_path("tagged.b.txt"), "1671670171235\n1671670171237\n"),
+        ];
+        for (path, contents) in &inputs {
+            fs::write(path, contents).unwrap();
+        }
+        let output = test_path("tagged.tmp.txt");
+        let sidecar = test_path("tagged.tmp.txt.src");
+
+        {
+            let sorted_files: Vec<_> = inputs
+                .iter()
+                .map(|(path, _)| SortedFile::new(path.to_str().unwrap()).unwrap())
+                .collect();
+            let mut output = OutputFile::new(output.to_str().unwrap(), 1 << 12).unwrap();
+            let sidecar = OutputFile::new(sidecar.to_str().unwrap(), 1 << 12).unwrap();
+            SortingWriter::new(sorted_files)
+                .with_tags(vec![b"a".to_vec(), b"bb".to_vec()])
+                .with_sidecar(sidecar)
+                .write_to(&mut output)
+                .unwrap();
+        }
+
+        assert_eq!(
+            "1671670171235\tbb\n\
+             1671670171236\ta\n\
+             1671670171237\ta\n\
+             1671670171237\tbb\n",
+            fs::read_to_string(&output).unwrap()
+        );
+        assert_eq!(vec![1, 0, 0, 1], fs::read(&sidecar).unwrap());
+    }
+
+    #[test]
+    fn test_tie_break_policies() {
+        let inputs = [
+            (
+                test_path("ties.a.txt"),
+                "0000000000001\ta1\n0000000000002\ta2\n0000000000002\ta3\n0000000000002\ta4\n",
+            ),
+            (
+                test_path("ties.b.txt"),
+                "0000000000001\tb1\n0000000000002\tb2\n0000000000002\tb3\n",
+            ),
+            (
+                test_path("ties.c.txt"),
+                "0000000000002\tc1\n0000000000003\tc2\n",
+            ),
+        ];
+        for (path, contents) in &inputs {
+            fs::write(path, contents).unwrap();
+        }
+        let output = test_path("ties.tmp.txt");
+
+        let merge_payloads = |tie_break| {
+            {
+                let record_files: Vec<_> = inputs
+                    .iter()
+                    .map(|(path, _)| RecordFile::<u64>::open(path.to_str().unwrap(), None).unwrap())
+                    .collect();
+                let mut output = OutputFile::new(output.to_str().unwrap(), 1 << 12).unwrap();
+                SortingWriter::new(record_files)
+                    .with_tie_break(tie_break)
+                    .write_to(&mut output)
+                    .unwrap();
+            }
+            let merged = fs::read_to_string(&output).unwrap();
+            merged
+                .lines()
+                .map(|l| l.split_once('\t').unwrap().1.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        assert_eq!(
+            "a1 b1 a2 a3 a4 b2 b3 c1 c2",
+            merge_payloads(TieBreak::InputOrder)
+        );
+        assert_eq!(
+            "b1 a1 c1 b2 b3 a2 a3 a4 c2",
+            merge_payloads(TieBreak::ReverseInputOrder)
+        );
+        assert_eq!(
+            "a1 b1 c1 a2 b2 a3 b3 a4 c2",
+            merge_payloads(TieBreak::RoundRobin)
+        );
+    }
+
+    #[test]
+    fn test_descending() {
+        let inputs = [
+            (
+                test_path("desc.a.txt"),
+                "1671670171239\n1671670171237\n1671670171235",
+            ),
+            (test_path("desc.b.txt"), "1671670171236\n1671670171238\n"),
+        ];
+        for (path, contents) in &inputs {
+            fs::write(path, contents).unwrap();
+        }
+        let paths: Vec<_> = inputs.iter().map(|(p, _)| p.to_str().unwrap()).collect();
+        assert_eq!(Order::Descending, detect_order(paths[0]).unwrap());
+        assert_eq!(Order::Ascending, detect_order(paths[1]).unwrap());
+        let output = test_path("desc.tmp.txt");
+
+        let merge_lines = |order| {
+            {
+                let sorted_files: Vec<_> = paths
+                    .iter()
+                    .map(|&path| {
+                        if detect_order(path).unwrap() == order {
+                            SortedFile::new(path).unwrap()
+                        } else {
+                            SortedFile::open_backwards(path, None).unwrap()
+                        }
+                    })
+                    .collect();
+                let mut output = OutputFile::new(output.to_str().unwrap(), 1 << 12).unwrap();
+                SortingWriter::new(sorted_files)
+                    .with_order(order)
+                    .write_to(&mut output)
+                    .unwrap();
+            }
+            fs::read_to_string(&output).unwrap()
+        };
+
+        assert_eq!(
+            "1671670171235\n\
+             1671670171236\n\
+             1671670171237\n\
+             1671670171238\n\
+             1671670171239\n",
+            merge_lines(Order::Ascending)
+        );
+        assert_eq!(
+            "1671670171239\n\
+             1671670171238\n\
+             1671670171237\n\
+             1671670171236\n\
+             1671670171235\n",
+            merge_lines(Order::Descending)
+        );
+    }
+
+    #[test]
+    fn test_iso_timestamps() {
+        let inputs = [
+            (
+                test_path("iso.a.txt"),
+                "1671671005500\ta\n1671671005596\tb\n",
+            ),
+            (test_path("iso.b.txt"), "1671671006007\tc\n"),
+        ];
+        for (path, contents) in &inputs {
+            fs::write(path, contents).unwrap();
+        }
+        let output = test_path("iso.tmp.txt");
+
+        {
+            let record_files: Vec<_> = inputs
+                .iter()
+                .map(|(path, _)| RecordFile::<u64>::open(path.to_str().unwrap(), None).unwrap())
+                .collect();
+            let mut output = OutputFile::new(output.to_str().unwrap(), 1 << 12).unwrap();
+            SortingWriter::new(record_files)
+                .with_iso_timestamps(IsoFormatter::new(UtcOffset::default()))
+                .with_tags(vec![b"0".to_vec(), b"1".to_vec()])
+                .write_to(&mut output)
+                .unwrap();
+        }
+
+        assert_eq!(
+            "2022-12-22T01:03:25.500Z\ta\t0\n\
+             2022-12-22T01:03:25.596Z\tb\t0\n\
+             2022-12-22T01:03:26.007Z\tc\t1\n",
+            fs::read_to_string(&output).unwrap()
+        );
+    }
+
     fn stdlib_solution_iter(file_names: &[&str]) -> impl Iterator<Item = u64> {
         let mut res = Vec::new();
         for f in file_names {
@@ -204,14 +819,3 @@ mod tests {
         res.into_iter()
     }
 }
-
-// Flagged as dead code unfortunately
-#[allow(dead_code)]
-const fn check_consts() {
-    assert!(
-        ALIGN >= LINE_WIDTH_INCL_NEWLINE,
-        "align size has to be atleast as big as one line to deal with parsing partial lines"
-    )
-}
-
-const _: () = check_consts();
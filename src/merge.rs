@@ -0,0 +1,336 @@
+use std::{cmp::Reverse, io, str::FromStr};
+
+use crate::{
+    aggregate::Aggregator,
+    cancel::CancelToken,
+    iodirect::{
+        key_index::KeyIndexWriter,
+        output_file::{IsoFormatter, OutputFile},
+        sorted_file::SortedFile,
+        InputStats, LineSink, MergeInput, Order,
+    },
+    progress::{Progress, ProgressReporter},
+    simd_decimal::PackedKey,
+};
+
+/// Lines between two looks at the cancel token and at the clock for
+/// progress reports.
+const CHECK_IN_LINES: u64 = 1 << 16;
+
+/// Merges sorted inputs into a single sorted output. When several inputs
+/// have the same key at their head, `TieBreak` decides which one gets
+/// written first. The default is input order, which makes the merge
+/// stable with respect to the order of the inputs.
+///
+/// All inputs have to be sorted in the output order. Descending files
+/// get there by being opened with `SortedFile::open_backwards` when the
+/// output is ascending, and the other way around.
+pub struct SortingWriter<I: MergeInput = SortedFile> {
+    pub(crate) inputs: Vec<I>,
+    tie_break: TieBreak,
+    order: Order,
+    // where TieBreak::RoundRobin starts looking for the minimum
+    round_robin_next: usize,
+    // appended to every line from the input with the same index
+    tags: Option<Vec<Vec<u8>>>,
+    // gets one byte per line: the index of the input it came from
+    sidecar: Option<OutputFile>,
+    // gets the key and offset of every line
+    index: Option<KeyIndexWriter<I::Key>>,
+    // renders the key at the start of every line as a timestamp
+    iso: Option<IsoFormatter>,
+    stats: MergeStats,
+    // key of the line written last, to count duplicates
+    last_key: Option<I::Key>,
+    progress: Option<ProgressReporter>,
+    cancel: Option<CancelToken>,
+}
+
+/// What merging took, apart from reading and writing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MergeStats {
+    /// Lines written, or keys aggregated.
+    pub lines: u64,
+    /// Bytes of the lines written.
+    pub bytes: u64,
+    /// Lines with the same key as the line before them.
+    pub duplicates: u64,
+    /// Keys compared to pick the next line.
+    pub comparisons: u64,
+}
+
+/// Which input wins when several of them have the same key at their head.
+/// Lines from the same input always keep their relative order.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TieBreak {
+    /// The input that comes first in the input list.
+    #[default]
+    InputOrder,
+    /// The input that comes last in the input list.
+    ReverseInputOrder,
+    /// The first tied input after the one that was written last, wrapping
+    /// around at the end of the input list. Runs of equal keys alternate
+    /// between the inputs that have them.
+    RoundRobin,
+}
+
+impl FromStr for TieBreak {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "input" => Ok(Self::InputOrder),
+            "reverse" => Ok(Self::ReverseInputOrder),
+            "round-robin" => Ok(Self::RoundRobin),
+            _ => Err(format!(
+                "unknown tie break policy: {s}, expected input, reverse or round-robin"
+            )),
+        }
+    }
+}
+
+impl<I: MergeInput> SortingWriter<I> {
+    pub fn new(sfs: Vec<I>) -> Self {
+        Self {
+            inputs: sfs,
+            tie_break: TieBreak::default(),
+            order: Order::default(),
+            round_robin_next: 0,
+            tags: None,
+            sidecar: None,
+            index: None,
+            iso: None,
+            stats: MergeStats::default(),
+            last_key: None,
+            progress: None,
+            cancel: None,
+        }
+    }
+
+    pub fn with_tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    pub fn with_order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Appends `<TAB>tag` to every line, where the tag is picked by the
+    /// index of the input the line came from.
+    pub fn with_tags(mut self, tags: Vec<Vec<u8>>) -> Self {
+        assert_eq!(tags.len(), self.inputs.len(), "need one tag per input");
+        self.tags = Some(tags);
+        self
+    }
+
+    /// Replaces the epoch millisecond key at the start of every line with
+    /// its ISO-8601 rendering. Whatever follows the key is kept.
+    pub fn with_iso_timestamps(mut self, iso: IsoFormatter) -> Self {
+        self.iso = Some(iso);
+        self
+    }
+
+    /// Writes the index of the input every line came from as a single
+    /// byte to `sidecar`, so the n-th byte belongs to the n-th line.
+    pub fn with_sidecar(mut self, sidecar: OutputFile) -> Self {
+        assert!(
+            self.inputs.len() <= u8::MAX as usize + 1,
+            "input index has to fit in a byte"
+        );
+        self.sidecar = Some(sidecar);
+        self
+    }
+
+    /// Records the key and offset of lines in the output in a sparse
+    /// index, see `KeyIndexWriter`. Only works for a single output file.
+    pub fn with_index(mut self, index: KeyIndexWriter<I::Key>) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    pub fn stats(&self) -> MergeStats {
+        self.stats
+    }
+
+    /// What reading every input took so far, in input order.
+    pub fn input_stats(&self) -> Vec<InputStats> {
+        self.inputs.iter().map(MergeInput::stats).collect()
+    }
+
+    /// Reports how far the merge got to `progress`, based on how much of
+    /// the inputs got read so far.
+    pub fn with_progress(mut self, mut progress: ProgressReporter) -> Self {
+        progress.total_bytes = self.inputs.iter().map(MergeInput::file_size).sum();
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Stops the merge at a line boundary once `cancel` gets cancelled.
+    /// A sidecar or index written with `OutputFile::new_atomic` goes away
+    /// when this is dropped without `finish`. The output is up to the
+    /// caller, see `OutputFile::discard`.
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Publishes the sidecar and index, if any. The output itself belongs
+    /// to the caller, who publishes it first so that these never end up
+    /// next to an output that is missing or stale. Fails without
+    /// publishing anything if the merge got cancelled after all, e.g.
+    /// right after the last line.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(cancel) = &self.cancel {
+            cancel.check()?;
+        }
+        if let Some(sidecar) = self.sidecar.take() {
+            sidecar.finish()?;
+        }
+        match self.index.take() {
+            Some(index) => index.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn write_to(&mut self, dest: &mut impl LineSink) -> io::Result<()> {
+        while let Some(idx) = self.pick_next() {
+            let min_sf = &mut self.inputs[idx];
+
+            let Some(line) = min_sf.peek_bytes() else {
+                break;
+            };
+            let key = *min_sf.peek().unwrap();
+            Self::count(&mut self.stats, &mut self.last_key, key);
+            if self.tags.is_none() && self.iso.is_none() {
+                self.stats.bytes += line.len() as u64;
+                let dest = dest.start_line(key, line.len())?;
+                if let Some(index) = &mut self.index {
+                    index.push(key, dest.position())?;
+                }
+                dest.write_bytes(line)?;
+            } else {
+                let (newline, mut line) = line.split_last().unwrap();
+                let mut iso_key = None;
+                if let Some(iso) = &mut self.iso {
+                    iso_key = Some(iso.format(key.millis("--iso")?));
+                    let digits = line.iter().take_while(|b| b.is_ascii_digit()).count();
+                    line = &line[digits..];
+                }
+                let tag = self.tags.as_ref().map(|tags| &tags[idx]);
+
+                let len = iso_key.map_or(0, <[u8]>::len)
+                    + line.len()
+                    + tag.map_or(0, |tag| tag.len() + 1)
+                    + 1;
+                self.stats.bytes += len as u64;
+                let dest = dest.start_line(key, len)?;
+                if let Some(index) = &mut self.index {
+                    index.push(key, dest.position())?;
+                }
+                if let Some(iso_key) = iso_key {
+                    dest.write_bytes(iso_key)?;
+                }
+                dest.write_bytes(line)?;
+                if let Some(tag) = tag {
+                    dest.write_bytes(b"\t")?;
+                    dest.write_bytes(tag)?;
+                }
+                dest.write_bytes(&[*newline])?;
+            }
+            if let Some(sidecar) = &mut self.sidecar {
+                sidecar.write_bytes(&[idx as u8])?;
+            }
+            min_sf.next()?;
+            if self.stats.lines % CHECK_IN_LINES == 0 {
+                self.check_in()?;
+            }
+        }
+        self.report_progress(true);
+        Ok(())
+    }
+
+    /// Feeds the merged keys, as numbers, to `agg` instead of writing the
+    /// lines themselves.
+    pub fn aggregate_to(&mut self, agg: &mut Aggregator, dest: &mut OutputFile) -> io::Result<()> {
+        while let Some(idx) = self.pick_next() {
+            let min_sf = &mut self.inputs[idx];
+            let Some(&key) = min_sf.peek() else {
+                break;
+            };
+            Self::count(&mut self.stats, &mut self.last_key, key);
+            agg.push(key.millis("--aggregate")?, dest)?;
+            min_sf.next()?;
+            if self.stats.lines % CHECK_IN_LINES == 0 {
+                self.check_in()?;
+            }
+        }
+        self.report_progress(true);
+        agg.finish(dest)
+    }
+
+    #[inline]
+    fn count(stats: &mut MergeStats, last_key: &mut Option<I::Key>, key: I::Key) {
+        stats.lines += 1;
+        if *last_key == Some(key) {
+            stats.duplicates += 1;
+        }
+        *last_key = Some(key);
+    }
+
+    /// Runs every `CHECK_IN_LINES` lines, in between two lines.
+    fn check_in(&mut self) -> io::Result<()> {
+        if let Some(cancel) = &self.cancel {
+            cancel.check()?;
+        }
+        self.report_progress(false);
+        Ok(())
+    }
+
+    fn report_progress(&mut self, done: bool) {
+        let Some(progress) = &mut self.progress else {
+            return;
+        };
+        progress.report(Progress {
+            lines: self.stats.lines,
+            bytes_written: self.stats.bytes,
+            bytes_read: self.inputs.iter().map(|input| input.stats().bytes).sum(),
+            key: self.last_key.map_or(0, Into::into),
+            done,
+            ..Default::default()
+        });
+    }
+
+    /// Returns the index of the input with the smallest key, or the
+    /// largest one for descending output. Exhausted inputs lose against
+    /// any key.
+    #[inline]
+    pub(crate) fn pick_next(&mut self) -> Option<usize> {
+        self.stats.comparisons += self.inputs.len().saturating_sub(1) as u64;
+        match self.order {
+            Order::Ascending => self.pick_by(|input| *input.peek().unwrap_or(&I::Key::MAX)),
+            // None sorts before any key, so it comes last once reversed
+            Order::Descending => self.pick_by(|input| Reverse(input.peek().copied())),
+        }
+    }
+
+    #[inline]
+    fn pick_by<T: Ord>(&mut self, key: impl Fn(&I) -> T) -> Option<usize> {
+        let inputs = &self.inputs;
+        let key = |&i: &usize| key(&inputs[i]);
+
+        // min_by_key returns the first of several equal minimums, so the
+        // order of the indexes is what implements the tie break policy
+        match self.tie_break {
+            TieBreak::InputOrder => (0..inputs.len()).min_by_key(key),
+            TieBreak::ReverseInputOrder => (0..inputs.len()).rev().min_by_key(key),
+            TieBreak::RoundRobin => {
+                let start = self.round_robin_next;
+                let idx = (start..inputs.len()).chain(0..start).min_by_key(key)?;
+                self.round_robin_next = (idx + 1) % inputs.len();
+                Some(idx)
+            }
+        }
+    }
+}
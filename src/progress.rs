@@ -0,0 +1,180 @@
+//! Progress of long merges, see `SortingWriter::with_progress`.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Where a merge is at.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Lines written, or keys aggregated.
+    pub lines: u64,
+    pub bytes_written: u64,
+    /// Bytes read from all inputs. Reads run a few buffers ahead of the
+    /// merge, which is close enough for files worth watching.
+    pub bytes_read: u64,
+    /// Size of all inputs.
+    pub total_bytes: u64,
+    pub elapsed: Duration,
+    /// Bytes read per second since the report before.
+    pub bytes_per_sec: f64,
+    /// Packed key of the line written last, `{:x}` prints its digits.
+    pub key: u128,
+    /// Whether this is the last report, sent once all lines are merged.
+    pub done: bool,
+}
+
+impl Progress {
+    pub fn percent(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 100.0;
+        }
+        (self.bytes_read as f64 * 100.0 / self.total_bytes as f64).min(100.0)
+    }
+
+    /// Time until all inputs are read at the current throughput.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.done {
+            return Some(Duration::ZERO);
+        }
+        let left = self.total_bytes.saturating_sub(self.bytes_read);
+        (self.bytes_per_sec > 0.0)
+            .then(|| Duration::from_secs_f64(left as f64 / self.bytes_per_sec))
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MIB: f64 = (1 << 20) as f64;
+        write!(
+            f,
+            "{:5.1}%, {:.0} MiB written, {:.0} MiB/s",
+            self.percent(),
+            self.bytes_written as f64 / MIB,
+            self.bytes_per_sec / MIB
+        )?;
+        if let Some(eta) = self.eta() {
+            let secs = eta.as_secs();
+            write!(
+                f,
+                ", eta {}:{:02}:{:02}",
+                secs / 3600,
+                secs / 60 % 60,
+                secs % 60
+            )?;
+        }
+        if self.lines > 0 {
+            write!(f, ", key {:x}", self.key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hands a `Progress` to a callback at most once per interval, and once
+/// more when the merge is done.
+pub struct ProgressReporter {
+    interval: Duration,
+    callback: Box<dyn FnMut(&Progress)>,
+    started: Instant,
+    // time and bytes read of the report before
+    last: Instant,
+    last_bytes_read: u64,
+    pub(crate) total_bytes: u64,
+}
+
+impl ProgressReporter {
+    pub fn new(interval: Duration, callback: impl FnMut(&Progress) + 'static) -> Self {
+        let now = Instant::now();
+        Self {
+            interval,
+            callback: Box::new(callback),
+            started: now,
+            last: now,
+            last_bytes_read: 0,
+            total_bytes: 0,
+        }
+    }
+
+    /// Prints a line to stderr every `interval`.
+    pub fn stderr(interval: Duration) -> Self {
+        Self::new(interval, |progress| eprintln!("{progress}"))
+    }
+
+    /// Reports `progress` if the interval is over. Fills in everything
+    /// that is about time.
+    pub(crate) fn report(&mut self, mut progress: Progress) {
+        let now = Instant::now();
+        let since_last = now - self.last;
+        if since_last < self.interval && !progress.done {
+            return;
+        }
+        progress.total_bytes = self.total_bytes;
+        progress.elapsed = now - self.started;
+        // the last report is usually only a moment after the one before
+        let (bytes, secs) = if progress.done {
+            (progress.bytes_read, progress.elapsed.as_secs_f64())
+        } else {
+            (
+                progress.bytes_read - self.last_bytes_read,
+                since_last.as_secs_f64(),
+            )
+        };
+        if secs > 0.0 {
+            progress.bytes_per_sec = bytes as f64 / secs;
+        }
+        self.last = now;
+        self.last_bytes_read = progress.bytes_read;
+        (self.callback)(&progress);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let sink = reports.clone();
+        let mut reporter = ProgressReporter::new(Duration::from_secs(3600), move |p| {
+            sink.borrow_mut().push(*p)
+        });
+        reporter.total_bytes = 400;
+
+        let progress = Progress {
+            lines: 10,
+            bytes_written: 100,
+            bytes_read: 100,
+            key: 0x1234,
+            ..Default::default()
+        };
+        // too early
+        reporter.report(progress);
+        assert!(reports.borrow().is_empty());
+        reporter.report(Progress {
+            done: true,
+            ..progress
+        });
+        let reports = reports.borrow();
+        assert_eq!(1, reports.len());
+        assert_eq!(25.0, reports[0].percent());
+        assert_eq!(Some(Duration::ZERO), reports[0].eta());
+        assert!(reports[0].to_string().starts_with(" 25.0%, 0 MiB written"));
+        assert!(reports[0].to_string().ends_with(", key 1234"));
+
+        let halfway = Progress {
+            bytes_read: 50,
+            total_bytes: 100,
+            bytes_per_sec: 10.0,
+            ..Default::default()
+        };
+        assert_eq!(Some(Duration::from_secs(5)), halfway.eta());
+        assert_eq!(
+            " 50.0%, 0 MiB written, 0 MiB/s, eta 0:00:05",
+            halfway.to_string()
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, ErrorKind, Seek, SeekFrom, Write},
+};
+
+use crate::iodirect::{detect_order, key_index::KeyIndex, Order};
+
+/// Runs `query <file> <key> [<last key>]`, printing the lines of a sorted
+/// file with keys from `key` up to and including `last key`. Returns the
+/// number of lines printed.
+pub fn run(path: &str, from: u128, to: u128) -> io::Result<u64> {
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    query(path, from, to, &mut out)
+        .and_then(|n| out.flush().map(|_| n))
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to query {path}: {e}")))
+}
+
+/// Parses a key given on the command line into the packed form, i.e.
+/// BCD, that key indexes use.
+pub fn parse_key(s: &str) -> Result<u128, String> {
+    if s.is_empty() || s.len() > 32 || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("invalid key: {s}, expected up to 32 digits"));
+    }
+    Ok(packed(s.as_bytes()))
+}
+
+/// Writes the lines of the sorted file at `path` whose keys are between
+/// `from` and `to`, inclusive, to `out`, in the order of the file. Starts
+/// reading close to the first of them when the file has a key index next
+/// to it, see `KeyIndex`, and from its start otherwise.
+pub fn query(path: &str, from: u128, to: u128, out: &mut impl Write) -> io::Result<u64> {
+    let index = match KeyIndex::open(&format!("{path}.idx")) {
+        Ok(index) => Some(index),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let order = match &index {
+        Some(index) => index.order(),
+        None => detect_order(path)?,
+    };
+
+    let (lo, hi) = (from.min(to), from.max(to));
+    // the end of the range that comes first in the file
+    let first = match order {
+        Order::Ascending => lo,
+        Order::Descending => hi,
+    };
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(index.map_or(0, |index| index.seek(first))))?;
+
+    let mut reader = BufReader::with_capacity(1 << 20, file);
+    let mut line = Vec::new();
+    let mut found = 0;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(found);
+        }
+        let digits = line.iter().take_while(|b| b.is_ascii_digit()).count();
+        let key = packed(&line[..digits]);
+        let (before, after) = match order {
+            Order::Ascending => (key < lo, key > hi),
+            Order::Descending => (key > hi, key < lo),
+        };
+        if after {
+            return Ok(found);
+        }
+        if before {
+            continue;
+        }
+        out.write_all(&line)?;
+        if !line.ends_with(b"\n") {
+            out.write_all(b"\n")?;
+        }
+        found += 1;
+    }
+}
+
+/// Packs digits of any count up to 32 into a key that compares by value.
+pub(crate) fn packed(digits: &[u8]) -> u128 {
+    digits
+        .iter()
+        .fold(0, |key, &d| key << 4 | (d - b'0') as u128)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iodirect::{
+        key_index::{IndexSpacing, KeyIndexWriter},
+        output_file::OutputFile,
+    };
+
+    #[test]
+    fn test_query() {
+        let path = crate::test_path("query.txt");
+        let path = path.to_str().unwrap();
+        let idx_path = format!("{path}.idx");
+        let _ = fs::remove_file(&idx_path);
+        let keys: Vec<u64> = (0..100).map(|i| 1671670171000 + i / 3).collect();
+        let contents: String = keys.iter().map(|k| format!("{k}\tp\n")).collect();
+        fs::write(path, &contents).unwrap();
+
+        let query = |from: &str, to: &str| {
+            let mut out = Vec::new();
+            let n = query(
+                path,
+                parse_key(from).unwrap(),
+                parse_key(to).unwrap(),
+                &mut out,
+            )
+            .unwrap();
+            assert_eq!(n, out.iter().filter(|&&b| b == b'\n').count() as u64);
+            String::from_utf8(out).unwrap()
+        };
+        let expected = "1671670171010\tp\n".repeat(3) + &"1671670171011\tp\n".repeat(3);
+        assert_eq!(expected, query("1671670171010", "1671670171011"));
+        assert_eq!(expected, query("1671670171011", "1671670171010"));
+        assert_eq!("", query("1671670172000", "1671670172000"));
+
+        // the same with an index, every 4th line
+        let out = OutputFile::new(&idx_path, 0).unwrap();
+        let mut index =
+            KeyIndexWriter::<u64>::new(out, IndexSpacing::Lines(4), Order::Ascending).unwrap();
+        for (i, &key) in keys.iter().enumerate() {
+            let key = packed(key.to_string().as_bytes()) as u64;
+            index.push(key, i as u64 * 16).unwrap();
+        }
+        index.finish().unwrap();
+        assert_eq!(expected, query("1671670171010", "1671670171011"));
+        assert_eq!(contents, query("1", "9999999999999"));
+        fs::remove_file(&idx_path).unwrap();
+
+        // descending files get read in their own order
+        let descending: String = contents.split_inclusive('\n').rev().collect();
+        fs::write(path, descending).unwrap();
+        let expected: String = expected.split_inclusive('\n').rev().collect();
+        assert_eq!(expected, query("1671670171010", "1671670171011"));
+
+        assert!(parse_key("12a").is_err());
+        assert!(parse_key("").is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! Statistics of a merge run as JSON, to see where the time goes on a
+//! given machine.
+
+use std::{
+    io::{self, Write},
+    time::Duration,
+};
+
+use crate::{
+    iodirect::{output_file::OutputStats, InputStats},
+    merge::MergeStats,
+};
+
+/// Everything `--report` writes. Times are wall clock, except for the
+/// read and write times, which are spent blocked in the calls themselves.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Report {
+    pub inputs: Vec<(String, InputStats)>,
+    pub merge: MergeStats,
+    pub output: OutputStats,
+    /// Detecting the line widths and opening the inputs and outputs.
+    pub open_time: Duration,
+    /// Reading, merging and handing the lines to the writer thread.
+    pub merge_time: Duration,
+    /// Waiting for the writes to finish and publishing the output.
+    pub finish_time: Duration,
+}
+
+impl Report {
+    pub fn total_time(&self) -> Duration {
+        self.open_time + self.merge_time + self.finish_time
+    }
+
+    pub fn write_json(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"inputs\": [")?;
+        for (i, (path, input)) in self.inputs.iter().enumerate() {
+            let sep = if i + 1 < self.inputs.len() { "," } else { "" };
+            writeln!(
+                out,
+                "    {{\"path\": \"{}\", \"lines\": {}, \"bytes\": {}, \"refills\": {}, \
+                 \"read_secs\": {}}}{sep}",
+                escaped(path),
+                input.lines,
+                input.bytes,
+                input.refills,
+                secs(input.read_time),
+            )?;
+        }
+        writeln!(out, "  ],")?;
+
+        let merge = &self.merge;
+        writeln!(
+            out,
+            "  \"merge\": {{\"lines\": {}, \"bytes\": {}, \"duplicates\": {}, \"comparisons\": {}}},",
+            merge.lines, merge.bytes, merge.duplicates, merge.comparisons
+        )?;
+        let output = &self.output;
+        writeln!(
+            out,
+            "  \"output\": {{\"bytes\": {}, \"buffers\": {}, \"new_buffers\": {}, \
+             \"write_secs\": {}}},",
+            output.bytes,
+            output.buffers,
+            output.new_buffers,
+            secs(output.write_time)
+        )?;
+        writeln!(
+            out,
+            "  \"phases\": {{\"open_secs\": {}, \"merge_secs\": {}, \"finish_secs\": {}, \
+             \"total_secs\": {}}},",
+            secs(self.open_time),
+            secs(self.merge_time),
+            secs(self.finish_time),
+            secs(self.total_time())
+        )?;
+
+        let input_bytes: u64 = self.inputs.iter().map(|(_, input)| input.bytes).sum();
+        let total = self.total_time().as_secs_f64();
+        let per_sec = |n: u64| if total > 0.0 { n as f64 / total } else { 0.0 };
+        writeln!(
+            out,
+            "  \"throughput\": {{\"input_bytes_per_sec\": {:.0}, \"output_bytes_per_sec\": {:.0}, \
+             \"lines_per_sec\": {:.0}}}",
+            per_sec(input_bytes),
+            per_sec(output.bytes),
+            per_sec(merge.lines)
+        )?;
+        writeln!(out, "}}")
+    }
+}
+
+fn secs(d: Duration) -> String {
+    format!("{:.6}", d.as_secs_f64())
+}
+
+/// Escapes `s` for use within a JSON string.
+fn escaped(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\t' => ret.push_str("\\t"),
+            c if c < ' ' => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_json() {
+        let input = InputStats {
+            lines: 3,
+            bytes: 42,
+            refills: 1,
+            read_time: Duration::from_millis(250),
+        };
+        let report = Report {
+            inputs: vec![
+                ("a\"b.txt".to_string(), input),
+                ("c\\d\u{1}".to_string(), input),
+            ],
+            merge: MergeStats {
+                lines: 6,
+                bytes: 84,
+                duplicates: 2,
+                comparisons: 6,
+            },
+            output: OutputStats {
+                bytes: 84,
+                buffers: 1,
+                new_buffers: 1,
+                write_time: Duration::from_micros(5),
+            },
+            open_time: Duration::from_millis(500),
+            merge_time: Duration::from_millis(1000),
+            finish_time: Duration::from_millis(500),
+        };
+        let mut json = Vec::new();
+        report.write_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+
+        for expected in [
+            r#"{"path": "a\"b.txt", "lines": 3, "bytes": 42, "refills": 1, "read_secs": 0.250000},"#,
+            r#"{"path": "c\\d\u0001", "lines": 3"#,
+            r#""merge": {"lines": 6, "bytes": 84, "duplicates": 2, "comparisons": 6},"#,
+            r#""output": {"bytes": 84, "buffers": 1, "new_buffers": 1, "write_secs": 0.000005},"#,
+            r#""total_secs": 2.000000}"#,
+            r#""throughput": {"input_bytes_per_sec": 42, "output_bytes_per_sec": 42, "lines_per_sec": 3}"#,
+        ] {
+            assert!(json.contains(expected), "{expected} not in {json}");
+        }
+        assert!(json.starts_with("{\n") && json.ends_with("}\n"));
+    }
+}
@@ -1,18 +1,233 @@
 use std::{
     arch::x86_64::{
-        __m128i, _mm_and_si128, _mm_cvtsi128_si64, _mm_loadu_si128, _mm_or_si128, _mm_packus_epi16,
-        _mm_set1_epi8, _mm_setr_epi8, _mm_setzero_si128, _mm_slli_epi16, _mm_slli_si128,
-        _mm_sub_epi8,
+        __m128i, _mm_add_epi8, _mm_and_si128, _mm_cmpeq_epi8, _mm_cvtsi128_si64, _mm_cvtsi64_si128,
+        _mm_loadu_si128, _mm_madd_epi16, _mm_maddubs_epi16, _mm_movemask_epi8, _mm_packus_epi16,
+        _mm_packus_epi32, _mm_set1_epi8, _mm_setr_epi16, _mm_setr_epi8, _mm_setzero_si128,
+        _mm_shuffle_epi8, _mm_srli_epi16, _mm_storeu_si128, _mm_sub_epi8, _mm_unpacklo_epi8,
     },
-    io::BufRead,
+    io::{self, BufRead},
     mem::MaybeUninit,
 };
 
 const REG_BYTES: usize = 16;
 
-pub fn parse_packed_4bit<const N: usize, const LINE_WIDTH: usize>(
-    inputs: &[u8],
+/// Largest line, including the newline, that fits in a u64 when packed.
+/// The newline itself is never loaded into the register.
+pub const MAX_LINE_WIDTH: usize = REG_BYTES + 1;
+
+/// Largest line, including the newline, that fits in a u128 when packed.
+pub const MAX_WIDE_LINE_WIDTH: usize = 2 * REG_BYTES + 1;
+
+pub type PackedParser<K> = fn(&[u8], &mut Vec<K>);
+
+/// Integer types that 4bit packed numbers can be parsed into. Wider
+/// keys fit more digits at the cost of slower comparisons.
+pub trait PackedKey: Copy + Ord + Into<u128> + std::fmt::Debug + Send + 'static {
+    const MAX: Self;
+    const MAX_LINE_WIDTH: usize;
+
+    /// Picks the parser instance for a line width that is only known at
+    /// runtime.
+    fn parser(line_width: usize) -> Option<PackedParser<Self>>;
+
+    /// Parses the `key_digits` wide number at the start of every line in
+    /// `line_starts`. See `parse_packed_4bit_at`.
+    fn parse_at(buf: &[u8], line_starts: &[u32], key_digits: usize, outputs: &mut Vec<Self>);
+
+    /// Turns a packed key back into the number it was parsed from, unless
+    /// that does not fit in a u64.
+    fn to_u64(self) -> Option<u64>;
+
+    /// Same as `to_u64`, for keys of any width.
+    fn to_u128(self) -> u128;
+
+    /// The key as epoch milliseconds, for features such as aggregation
+    /// that need it as a u64. Keys too wide for that are InvalidData.
+    fn millis(self, feature: &str) -> io::Result<u64> {
+        self.to_u64().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{feature} needs keys that fit in a u64, got {}",
+                    self.to_u128()
+                ),
+            )
+        })
+    }
+}
+
+impl PackedKey for u64 {
+    const MAX: Self = u64::MAX;
+    const MAX_LINE_WIDTH: usize = MAX_LINE_WIDTH;
+
+    fn parser(line_width: usize) -> Option<PackedParser<Self>> {
+        packed_4bit_parser(line_width)
+    }
+
+    fn parse_at(buf: &[u8], line_starts: &[u32], key_digits: usize, outputs: &mut Vec<Self>) {
+        parse_packed_4bit_at(buf, line_starts, key_digits, outputs)
+    }
+
+    fn to_u64(self) -> Option<u64> {
+        Some(packed_4bit_to_u64(self))
+    }
+
+    fn to_u128(self) -> u128 {
+        packed_4bit_to_u64(self) as u128
+    }
+}
+
+impl PackedKey for u128 {
+    const MAX: Self = u128::MAX;
+    const MAX_LINE_WIDTH: usize = MAX_WIDE_LINE_WIDTH;
+
+    fn parser(line_width: usize) -> Option<PackedParser<Self>> {
+        macro_rules! dispatch {
+            ($($w:literal)*; $($wide:literal)*) => {
+                match line_width {
+                    $($w => Some(parse_packed_4bit::<6, $w, u128> as PackedParser<Self>),)*
+                    $($wide => Some(parse_packed_4bit_wide::<6, $wide> as PackedParser<Self>),)*
+                    _ => None,
+                }
+            };
+        }
+        dispatch!(
+            2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17;
+            18 19 20 21 22 23 24 25 26 27 28 29 30 31 32 33
+        )
+    }
+
+    fn parse_at(buf: &[u8], line_starts: &[u32], key_digits: usize, outputs: &mut Vec<Self>) {
+        parse_packed_4bit_at_wide(buf, line_starts, key_digits, outputs)
+    }
+
+    fn to_u64(self) -> Option<u64> {
+        self.to_u128().try_into().ok()
+    }
+
+    fn to_u128(self) -> u128 {
+        let hi = bcd_to_u64((self >> 64) as u64) as u128;
+        let lo = bcd_to_u64(self as u64) as u128;
+        hi * 10_u128.pow(16) + lo
+    }
+}
+
+/// Parses the leading `key_digits` digits of variable length lines, which
+/// start at the given offsets into `buf`. Whatever follows the key is
+/// ignored. Every line gets a full register loaded, so `buf` has to
+/// extend at least `REG_BYTES` past the start of the last line.
+pub fn parse_packed_4bit_at(
+    buf: &[u8],
+    line_starts: &[u32],
+    key_digits: usize,
     outputs: &mut Vec<u64>,
+) {
+    if let Some(&last) = line_starts.last() {
+        assert!(
+            last as usize + REG_BYTES <= buf.len(),
+            "buf is too short to load the last key"
+        );
+    }
+
+    outputs.clear();
+    outputs.reserve(line_starts.len());
+
+    unsafe {
+        let zero = _mm_set1_epi8(b'0' as i8);
+        let align_digits = right_align_digits_mask(key_digits);
+        for &start in line_starts {
+            let a = _mm_loadu_si128(buf.as_ptr().add(start as usize) as *const __m128i);
+            let digits = _mm_shuffle_epi8(_mm_sub_epi8(a, zero), align_digits);
+            outputs.push(pack_aligned_digits(digits));
+        }
+    }
+}
+
+/// Same as `parse_packed_4bit_at`, but for keys of up to 32 digits. `buf`
+/// has to extend at least `max(REG_BYTES, key_digits)` bytes past the
+/// start of the last line.
+pub fn parse_packed_4bit_at_wide(
+    buf: &[u8],
+    line_starts: &[u32],
+    key_digits: usize,
+    outputs: &mut Vec<u128>,
+) {
+    assert!(key_digits <= 2 * REG_BYTES, "only 32 digits fit in a u128");
+    if let Some(&last) = line_starts.last() {
+        assert!(
+            last as usize + key_digits.max(REG_BYTES) <= buf.len(),
+            "buf is too short to load the last key"
+        );
+    }
+
+    outputs.clear();
+    outputs.reserve(line_starts.len());
+
+    let hi_digits = key_digits.saturating_sub(REG_BYTES);
+    let lo_digits = key_digits - hi_digits;
+    unsafe {
+        let zero = _mm_set1_epi8(b'0' as i8);
+        let align_hi = right_align_digits_mask(hi_digits);
+        let align_lo = right_align_digits_mask(lo_digits);
+        for &start in line_starts {
+            let line = buf.as_ptr().add(start as usize);
+            let lo = _mm_loadu_si128(line.add(hi_digits) as *const __m128i);
+            let lo = _mm_shuffle_epi8(_mm_sub_epi8(lo, zero), align_lo);
+            let mut key = pack_aligned_digits(lo) as u128;
+            if hi_digits > 0 {
+                let hi = _mm_loadu_si128(line as *const __m128i);
+                let hi = _mm_shuffle_epi8(_mm_sub_epi8(hi, zero), align_hi);
+                key |= (pack_aligned_digits(hi) as u128) << 64;
+            }
+            outputs.push(key);
+        }
+    }
+}
+
+/// Appends the offset right after every newline in `buf` to `outputs`,
+/// i.e. where the next line starts. `base` gets added to every offset.
+pub fn newline_ends(buf: &[u8], base: usize, outputs: &mut Vec<u32>) {
+    let chunks = buf.chunks_exact(REG_BYTES);
+    let remainder = chunks.remainder();
+    let newline = unsafe { _mm_set1_epi8(b'\n' as i8) };
+    let mut off = base;
+    for chunk in chunks {
+        let mut found = unsafe {
+            let a = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+            _mm_movemask_epi8(_mm_cmpeq_epi8(a, newline)) as u32
+        };
+        while found != 0 {
+            let idx = found.trailing_zeros() as usize;
+            outputs.push((off + idx + 1) as u32);
+            // clear the lowest set bit
+            found &= found - 1;
+        }
+        off += REG_BYTES;
+    }
+    for (idx, &b) in remainder.iter().enumerate() {
+        if b == b'\n' {
+            outputs.push((off + idx + 1) as u32);
+        }
+    }
+}
+
+/// Picks the `parse_packed_4bit` instance for a line width that is only
+/// known at runtime.
+pub fn packed_4bit_parser(line_width: usize) -> Option<PackedParser<u64>> {
+    macro_rules! dispatch {
+        ($($w:literal)*) => {
+            match line_width {
+                $($w => Some(parse_packed_4bit::<6, $w, u64> as PackedParser<u64>),)*
+                _ => None,
+            }
+        };
+    }
+    dispatch!(2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17)
+}
+
+pub fn parse_packed_4bit<const N: usize, const LINE_WIDTH: usize, K: From<u64>>(
+    inputs: &[u8],
+    outputs: &mut Vec<K>,
 ) {
     let expected_results = inputs.len() / LINE_WIDTH;
     assert_eq!(inputs.len() % LINE_WIDTH, 0, "only pass complete lines");
@@ -21,30 +236,100 @@ pub fn parse_packed_4bit<const N: usize, const LINE_WIDTH: usize>(
     outputs.reserve(expected_results);
 
     let mut chunker = ChunkerIter::<LINE_WIDTH, REG_BYTES, N>::new(inputs);
-    let mut i = 0;
-    for chunk in &mut chunker {
-        unsafe {
-            do_parse_packed_4bit::<N, LINE_WIDTH>(
-                &chunk,
-                outputs.get_unchecked_mut(i..i + N).try_into().unwrap(),
-            );
-            i += N;
-        }
-    }
+    let mut dst = outputs.as_mut_ptr();
+    let mut parsed = [0_u64; N];
     unsafe {
+        for chunk in &mut chunker {
+            do_parse_packed_4bit::<N, LINE_WIDTH>(&chunk, &mut parsed);
+            for p in parsed {
+                dst.write(K::from(p));
+                dst = dst.add(1);
+            }
+        }
         for rem in chunker.remainder() {
             let mut buf = [0; REG_BYTES];
-            buf.get_unchecked_mut(..rem.len()).copy_from_slice(rem);
-            do_parse_packed_4bit::<1, LINE_WIDTH>(
-                &[&buf],
-                outputs.get_unchecked_mut(i..i + 1).try_into().unwrap(),
-            );
-            i += 1;
+            let n = rem.len().min(REG_BYTES);
+            buf[..n].copy_from_slice(&rem[..n]);
+            let mut parsed = [0_u64];
+            do_parse_packed_4bit::<1, LINE_WIDTH>(&[&buf], &mut parsed);
+            dst.write(K::from(parsed[0]));
+            dst = dst.add(1);
         }
         outputs.set_len(expected_results);
     }
 }
 
+/// Same as `parse_packed_4bit`, but for lines with more than 16 digits.
+/// Every line gets two registers: one holding the last 16 digits and one
+/// holding the rest, which become the lo and hi 64bits of the result.
+pub fn parse_packed_4bit_wide<const N: usize, const LINE_WIDTH: usize>(
+    inputs: &[u8],
+    outputs: &mut Vec<u128>,
+) {
+    let expected_results = inputs.len() / LINE_WIDTH;
+    assert_eq!(inputs.len() % LINE_WIDTH, 0, "only pass complete lines");
+
+    outputs.clear();
+    outputs.reserve(expected_results);
+
+    // lines are wider than registers here, so unlike ChunkerIter no load
+    // ever goes past the end of the line it starts in
+    let chunks = inputs.chunks_exact(N * LINE_WIDTH);
+    let remainder = chunks.remainder();
+    let mut dst = outputs.as_mut_ptr();
+    unsafe {
+        for chunk in chunks {
+            let lines: [&[u8; LINE_WIDTH]; N] =
+                std::array::from_fn(|i| chunk[i * LINE_WIDTH..][..LINE_WIDTH].try_into().unwrap());
+            do_parse_packed_4bit_wide::<N, LINE_WIDTH>(&lines, &mut *(dst as *mut [u128; N]));
+            dst = dst.add(N);
+        }
+        for rem in remainder.chunks_exact(LINE_WIDTH) {
+            let line = rem.try_into().unwrap();
+            do_parse_packed_4bit_wide::<1, LINE_WIDTH>(&[line], &mut *(dst as *mut [u128; 1]));
+            dst = dst.add(1);
+        }
+        outputs.set_len(expected_results);
+    }
+}
+
+unsafe fn do_parse_packed_4bit_wide<const N: usize, const LINE_WIDTH: usize>(
+    inputs: &[&[u8; LINE_WIDTH]; N],
+    outputs: &mut [u128; N],
+) {
+    let num_digits = LINE_WIDTH - 1;
+    assert!(
+        num_digits > REG_BYTES && num_digits <= 2 * REG_BYTES,
+        "only for lines that need two registers"
+    );
+    let hi_digits = num_digits - REG_BYTES;
+
+    let zero = _mm_set1_epi8(b'0' as i8);
+    let mut hi = [_mm_setzero_si128(); N];
+    let mut lo = [_mm_setzero_si128(); N];
+    for i in 0..N {
+        let line = inputs[i].as_ptr();
+        hi[i] = _mm_sub_epi8(_mm_loadu_si128(line as *const __m128i), zero);
+        lo[i] = _mm_sub_epi8(_mm_loadu_si128(line.add(hi_digits) as *const __m128i), zero);
+    }
+
+    // the lo register holds exactly 16 digits, only the hi one needs
+    // to be aligned
+    let align_digits = right_align_digits_mask(hi_digits);
+    for reg in &mut hi {
+        *reg = _mm_shuffle_epi8(*reg, align_digits);
+    }
+
+    for i in 0..N {
+        let hi = pack_aligned_digits(hi[i]) as u128;
+        let lo = pack_aligned_digits(lo[i]) as u128;
+        outputs[i] = hi << 64 | lo;
+    }
+}
+
+/// Digits are right aligned before packing, which makes the packed
+/// number plain BCD. That way lines of different widths still compare
+/// by their numeric value.
 unsafe fn do_parse_packed_4bit<const N: usize, const LINE_WIDTH: usize>(
     inputs: &[&[u8; REG_BYTES]; N],
     outputs: &mut [u64; N],
@@ -56,41 +341,225 @@ unsafe fn do_parse_packed_4bit<const N: usize, const LINE_WIDTH: usize>(
         cleaned[i] = _mm_sub_epi8(a, zero);
     }
 
-    let last3_mask = _mm_setr_epi8(
-        -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, 0x00, 0x00, 0x00,
-    );
-    for i in 0..N {
-        // zero out three bytes off the end since that's not part
-        // of the number
-        cleaned[i] = _mm_and_si128(cleaned[i], last3_mask);
+    let align_digits = right_align_digits_mask(LINE_WIDTH - 1);
+    for reg in &mut cleaned {
+        // move the digits to the end of the register, zeroing out the
+        // newline and whatever comes after it
+        *reg = _mm_shuffle_epi8(*reg, align_digits);
     }
 
-    for i in 0..N {
-        // shift left by 4bits so that we can do a logical OR
-        // to pack together 2 bytes into 1 byte
-        let b = _mm_slli_epi16(cleaned[i], 4);
-        // shift left by 1 byte so that things line up to do
-        // the OR operation
-        let b = _mm_slli_si128(b, 1);
-        // do the OR operation such that two adjacent bytes
-        // end up getting packed together into one byte
-        cleaned[i] = _mm_or_si128(cleaned[i], b);
-    }
-
-    let every_other_mask = _mm_setr_epi8(-1, 0, -1, 0, -1, 0, -1, 0, -1, 0, -1, 0, -1, 0, -1, 0);
-    for i in 0..N {
-        // zero out every other byte so that we can pack into 8bits later
-        cleaned[i] = _mm_and_si128(cleaned[i], every_other_mask);
+    for (out, reg) in outputs.iter_mut().zip(cleaned) {
+        *out = pack_aligned_digits(reg);
     }
-    for i in 0..N {
-        // pack 16bits into 8bits
-        cleaned[i] = _mm_packus_epi16(cleaned[i], cleaned[i]);
+}
+
+/// Packs a register of right aligned digits into 16 BCD nibbles.
+#[inline(always)]
+unsafe fn pack_aligned_digits(digits: __m128i) -> u64 {
+    // shift every even byte left by 4bits and add the odd byte to
+    // it, packing two digits into the low byte of each 16bit lane
+    let mul_16 = _mm_setr_epi8(16, 1, 16, 1, 16, 1, 16, 1, 16, 1, 16, 1, 16, 1, 16, 1);
+    let packed = _mm_maddubs_epi16(digits, mul_16);
+    // pack 16bits into 8bits
+    let packed = _mm_packus_epi16(packed, packed);
+    // extract the lo 64bits and swap bytes so that the most
+    // significant 4bits in the right place
+    _mm_cvtsi128_si64(packed).swap_bytes() as u64
+}
+
+/// pshufb mask that moves the digits of a line to the end of the
+/// register. 0x80 makes pshufb write a zero byte.
+#[inline]
+unsafe fn right_align_digits_mask(num_digits: usize) -> __m128i {
+    assert!(num_digits <= REG_BYTES, "only 16 digits fit in a register");
+
+    let shift = REG_BYTES - num_digits;
+    let mut shuffle = [0x80_u8; REG_BYTES];
+    for (j, idx) in shuffle.iter_mut().enumerate().skip(shift) {
+        *idx = (j - shift) as u8;
+    }
+    _mm_loadu_si128(shuffle.as_ptr() as *const __m128i)
+}
+
+/// Parses lines into their actual binary value. Unlike the 4bit packed
+/// form, the results can be used for arithmetic.
+pub fn parse_u64<const N: usize, const LINE_WIDTH: usize>(inputs: &[u8], outputs: &mut Vec<u64>) {
+    let expected_results = inputs.len() / LINE_WIDTH;
+    assert_eq!(inputs.len() % LINE_WIDTH, 0, "only pass complete lines");
+
+    outputs.clear();
+    outputs.reserve(expected_results);
+
+    let mut chunker = ChunkerIter::<LINE_WIDTH, REG_BYTES, N>::new(inputs);
+    let mut dst = outputs.as_mut_ptr();
+    unsafe {
+        for chunk in &mut chunker {
+            do_parse_u64::<N, LINE_WIDTH>(&chunk, &mut *(dst as *mut [u64; N]));
+            dst = dst.add(N);
+        }
+        for rem in chunker.remainder() {
+            let mut buf = [0; REG_BYTES];
+            let n = rem.len().min(REG_BYTES);
+            buf[..n].copy_from_slice(&rem[..n]);
+            do_parse_u64::<1, LINE_WIDTH>(&[&buf], &mut *(dst as *mut [u64; 1]));
+            dst = dst.add(1);
+        }
+        outputs.set_len(expected_results);
+    }
+}
+
+unsafe fn do_parse_u64<const N: usize, const LINE_WIDTH: usize>(
+    inputs: &[&[u8; REG_BYTES]; N],
+    outputs: &mut [u64; N],
+) {
+    // move the digits to the end of the register so that every number
+    // looks like a zero padded 16 digit number. This also zeroes out
+    // the newline and whatever comes after it.
+    let shuffle = right_align_digits_mask(LINE_WIDTH - 1);
+
+    let zero = _mm_set1_epi8(b'0' as i8);
+    let mut regs = [_mm_setzero_si128(); N];
+    for (reg, input) in regs.iter_mut().zip(inputs) {
+        let a = _mm_loadu_si128(*input as *const u8 as *const __m128i);
+        *reg = _mm_shuffle_epi8(_mm_sub_epi8(a, zero), shuffle);
+    }
+
+    let mul_10 = _mm_setr_epi8(10, 1, 10, 1, 10, 1, 10, 1, 10, 1, 10, 1, 10, 1, 10, 1);
+    for reg in &mut regs {
+        // combine adjacent digits into 16bit lanes holding 0..=99
+        *reg = _mm_maddubs_epi16(*reg, mul_10);
+    }
+    let mul_100 = _mm_setr_epi16(100, 1, 100, 1, 100, 1, 100, 1);
+    for reg in &mut regs {
+        // combine adjacent pairs into 32bit lanes holding 0..=9999
+        *reg = _mm_madd_epi16(*reg, mul_100);
+    }
+    for reg in &mut regs {
+        // every 32bit lane fits in 16bits now, so narrow them to be able
+        // to use madd once more
+        *reg = _mm_packus_epi32(*reg, *reg);
+    }
+    let mul_10000 = _mm_setr_epi16(10000, 1, 10000, 1, 10000, 1, 10000, 1);
+    for reg in &mut regs {
+        // the low two 32bit lanes now hold the upper and lower 8 digits
+        *reg = _mm_madd_epi16(*reg, mul_10000);
+    }
+    for (out, reg) in outputs.iter_mut().zip(regs) {
+        let halves = _mm_cvtsi128_si64(reg) as u64;
+        *out = (halves & 0xffff_ffff) * 100_000_000 + (halves >> 32);
+    }
+}
+
+/// Converts a 4bit packed number as produced by `parse_packed_4bit`
+/// into its binary value without going back to ascii.
+#[inline]
+pub fn packed_4bit_to_u64(packed: u64) -> u64 {
+    // packed numbers are right aligned, i.e. already plain BCD
+    bcd_to_u64(packed)
+}
+
+/// Converts 16 BCD digits into binary by repeatedly merging adjacent
+/// lanes of doubling width.
+#[inline]
+pub fn bcd_to_u64(bcd: u64) -> u64 {
+    let x = (bcd & 0x0f0f_0f0f_0f0f_0f0f) + ((bcd >> 4) & 0x0f0f_0f0f_0f0f_0f0f) * 10;
+    let x = (x & 0x00ff_00ff_00ff_00ff) + ((x >> 8) & 0x00ff_00ff_00ff_00ff) * 100;
+    let x = (x & 0x0000_ffff_0000_ffff) + ((x >> 16) & 0x0000_ffff_0000_ffff) * 10000;
+    (x & 0x0000_0000_ffff_ffff) + (x >> 32) * 100_000_000
+}
+
+/// Turns 4bit packed numbers produced by `parse_packed_4bit` back into
+/// ascii lines, including the trailing newline. `outputs` will contain
+/// exactly `inputs.len() * LINE_WIDTH` bytes.
+pub fn unpack_packed_4bit<const N: usize, const LINE_WIDTH: usize>(
+    inputs: &[u64],
+    outputs: &mut Vec<u8>,
+) {
+    let expected_bytes = inputs.len() * LINE_WIDTH;
+
+    outputs.clear();
+    // every line is stored as a full register, so the last store can
+    // spill over by up to REG_BYTES - LINE_WIDTH bytes
+    outputs.reserve(expected_bytes + REG_BYTES);
+
+    let chunks = inputs.chunks_exact(N);
+    let remainder = chunks.remainder();
+    let mut dst = outputs.as_mut_ptr();
+    unsafe {
+        for chunk in chunks {
+            do_unpack_packed_4bit::<N, LINE_WIDTH>(chunk.try_into().unwrap(), dst);
+            dst = dst.add(N * LINE_WIDTH);
+        }
+        for rem in remainder {
+            do_unpack_packed_4bit::<1, LINE_WIDTH>(&[*rem], dst);
+            dst = dst.add(LINE_WIDTH);
+        }
+        outputs.set_len(expected_bytes);
+    }
+}
+
+/// Single line version of `unpack_packed_4bit`.
+#[inline]
+pub fn unpack_packed_4bit_line<const LINE_WIDTH: usize>(packed: u64) -> [u8; LINE_WIDTH] {
+    // 16 digit lines are one byte longer than the register
+    let mut buf = [0_u8; REG_BYTES + 1];
+    unsafe { do_unpack_packed_4bit::<1, LINE_WIDTH>(&[packed], buf.as_mut_ptr()) };
+    buf[..LINE_WIDTH].try_into().unwrap()
+}
+
+/// Writes a full register for each line at `outputs + i * LINE_WIDTH`,
+/// so `outputs` must be valid for `(N - 1) * LINE_WIDTH + REG_BYTES + 1`
+/// bytes.
+unsafe fn do_unpack_packed_4bit<const N: usize, const LINE_WIDTH: usize>(
+    inputs: &[u64; N],
+    outputs: *mut u8,
+) {
+    let num_digits = LINE_WIDTH - 1;
+    assert!(num_digits <= REG_BYTES, "a u64 holds at most 16 digits");
+
+    // packed numbers are right aligned, so digit j lives in nibble
+    // j + shift. 0x80 makes pshufb write a zero byte.
+    let shift = 16 - num_digits;
+    let mut shuffle = [0x80_u8; REG_BYTES];
+    let mut ascii = [0_u8; REG_BYTES];
+    for j in 0..num_digits {
+        shuffle[j] = (j + shift) as u8;
+        ascii[j] = b'0';
+    }
+    // with 16 digits the newline gets stored on its own below
+    if num_digits < REG_BYTES {
+        ascii[num_digits] = b'\n';
     }
+    let shuffle = _mm_loadu_si128(shuffle.as_ptr() as *const __m128i);
+    let ascii = _mm_loadu_si128(ascii.as_ptr() as *const __m128i);
+    let lo_nibble_mask = _mm_set1_epi8(0x0f);
+
+    let mut unpacked = [_mm_setzero_si128(); N];
     for i in 0..N {
-        // extract the lo 64bits and swap bytes so that the most
-        // significant 4bits in the right place
-        let lo = _mm_cvtsi128_si64(cleaned[i]);
-        outputs[i] = lo.swap_bytes() as u64;
+        // undo the byte swap so that the most significant digits come
+        // first in memory order
+        let packed = _mm_cvtsi64_si128(inputs[i].swap_bytes() as i64);
+        let hi = _mm_and_si128(_mm_srli_epi16(packed, 4), lo_nibble_mask);
+        let lo = _mm_and_si128(packed, lo_nibble_mask);
+        // interleave so that every nibble ends up in its own byte
+        unpacked[i] = _mm_unpacklo_epi8(hi, lo);
+    }
+    for reg in &mut unpacked {
+        // drop the leading padding nibbles and zero out everything
+        // after the last digit
+        *reg = _mm_shuffle_epi8(*reg, shuffle);
+    }
+    for reg in &mut unpacked {
+        // turn digits into ascii and the byte after the last digit
+        // into a newline
+        *reg = _mm_add_epi8(*reg, ascii);
+    }
+    for (i, reg) in unpacked.iter().enumerate() {
+        let dst = outputs.add(i * LINE_WIDTH);
+        _mm_storeu_si128(dst as *mut __m128i, *reg);
+        if num_digits == REG_BYTES {
+            *dst.add(REG_BYTES) = b'\n';
+        }
     }
 }
 
@@ -100,7 +569,10 @@ struct ChunkerIter<'a, const L: usize, const R: usize, const N: usize> {
 
 impl<'a, const L: usize, const R: usize, const N: usize> ChunkerIter<'a, L, R, N> {
     fn new(slice: &'a [u8]) -> Self {
-        assert!(L <= R, "line cannot be longer than register width");
+        assert!(
+            L <= R + 1,
+            "line without the newline cannot be longer than register width"
+        );
         assert!(
             slice.len() % L == 0,
             "parse_decimal: ChunkIter can only handle complete lines"
@@ -121,13 +593,15 @@ impl<'a, const L: usize, const R: usize, const N: usize> Iterator for ChunkerIte
     type Item = [&'a [u8; R]; N];
 
     fn next(&mut self) -> Option<Self::Item> {
-        if N * R <= self.slice.len() {
+        // the last register loaded may extend past the last line it
+        // starts, so make sure that it's still within the slice
+        if (N - 1) * L + R <= self.slice.len() {
             let mut arr: [MaybeUninit<&'a [u8; R]>; N] = MaybeUninit::uninit_array();
             let mut buf = self.slice.as_ptr();
             for i in 0..N {
                 let as_ptr = buf as *const [u8; R];
-                // SAFETY: buf is guaranteed to be a valid address because we check that L <= R
-                // in the constructor and the slice is at least N * R long
+                // SAFETY: buf is guaranteed to be a valid address because the slice is at
+                // least (N - 1) * L + R long
                 let as_ref = unsafe {
                     buf = buf.add(L);
                     &*as_ptr as &'a [u8; R]
@@ -146,10 +620,7 @@ impl<'a, const L: usize, const R: usize, const N: usize> Iterator for ChunkerIte
 
 #[cfg(test)]
 mod tests {
-    use std::arch::x86_64::{
-        __m128i, _mm_and_si128, _mm_lddqu_si128, _mm_or_si128, _mm_packus_epi16, _mm_slli_epi16,
-        _mm_slli_si128,
-    };
+    use std::arch::x86_64::{__m128i, _mm_lddqu_si128, _mm_maddubs_epi16, _mm_packus_epi16};
 
     use super::*;
 
@@ -171,6 +642,78 @@ mod tests {
         assert_eq!(iter.next(), None);
     }
 
+    #[test]
+    fn test_unpack_packed_4bit() {
+        let input = "1671670171236\n1671670172236\n0000000000000\n9999999999999\n1671670171285\n";
+        let mut parsed = Vec::new();
+        parse_packed_4bit::<2, 14, u64>(input.as_bytes(), &mut parsed);
+
+        let mut unpacked = Vec::new();
+        unpack_packed_4bit::<2, 14>(&parsed, &mut unpacked);
+        assert_eq!(Ok(input), std::str::from_utf8(&unpacked));
+
+        assert_eq!(
+            b"1234567891234\n",
+            &unpack_packed_4bit_line::<14>(0x00_01_23_45_67_89_12_34)
+        );
+        assert_eq!(
+            b"0012345678\n",
+            &unpack_packed_4bit_line::<11>(0x12_34_56_78)
+        );
+
+        // the widest u64 keys leave no room for the newline in the register
+        let input = "1234567890123456\n0000000000000042\n9999999999999999\n";
+        let mut parsed = Vec::new();
+        parse_packed_4bit::<2, 17, u64>(input.as_bytes(), &mut parsed);
+        unpack_packed_4bit::<2, 17>(&parsed, &mut unpacked);
+        assert_eq!(Ok(input), std::str::from_utf8(&unpacked));
+        assert_eq!(
+            b"1234567890123456\n",
+            &unpack_packed_4bit_line::<17>(0x12_34_56_78_90_12_34_56)
+        );
+    }
+
+    #[test]
+    fn test_parse_u64() {
+        let input = "1671670171236\n0000000000000\n9999999999999\n0000000000042\n1671670171285\n";
+        let expected: Vec<u64> = input.lines().map(|l| l.parse().unwrap()).collect();
+
+        let mut parsed = Vec::new();
+        parse_u64::<2, 14>(input.as_bytes(), &mut parsed);
+        assert_eq!(expected, parsed);
+
+        let mut parsed = Vec::new();
+        parse_u64::<1, 11>(b"9999999999\n1671670171\n", &mut parsed);
+        assert_eq!(vec![9999999999, 1671670171], parsed);
+
+        let mut packed = Vec::new();
+        parse_packed_4bit::<2, 14, u64>(input.as_bytes(), &mut packed);
+        let converted: Vec<_> = packed.into_iter().map(packed_4bit_to_u64).collect();
+        assert_eq!(expected, converted);
+    }
+
+    #[test]
+    fn test_records() {
+        let input = "1671670171236\tGET /\n0000000000042\n9999999999999\tPOST /login HTTP/1.1\n";
+        let mut buf = input.as_bytes().to_vec();
+        buf.resize(input.len() + REG_BYTES, 0);
+
+        let mut line_starts = vec![0];
+        newline_ends(&buf[..input.len()], 0, &mut line_starts);
+        assert_eq!(vec![0, 20, 34, 69], line_starts);
+
+        let mut keys = Vec::new();
+        parse_packed_4bit_at(&buf, &line_starts[..3], 13, &mut keys);
+        assert_eq!(vec![0x1671670171236, 0x42, 0x9999999999999], keys);
+
+        let mut keys = Vec::new();
+        let input = "1671670171236000000\tGET /\n0000000000000000042\n";
+        let mut buf = input.as_bytes().to_vec();
+        buf.resize(input.len() + REG_BYTES, 0);
+        parse_packed_4bit_at_wide(&buf, &[0, 26], 19, &mut keys);
+        assert_eq!(vec![0x1671670171236000000, 0x42], keys);
+    }
+
     fn as_str<const R: usize, const N: usize>(res: [&[u8; R]; N]) -> [&str; N] {
         let vec: Vec<_> = res
             .into_iter()
@@ -195,39 +738,75 @@ mod tests {
             let a = _mm_sub_epi8(a, zero);
             eprintln!("a - 0\t: {:02x}", as_portable_simd(a));
 
-            // zero out three bytes off the end since that's not part
-            // of the number
-            let and_mask = _mm_setr_epi8(
-                -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, 0x00, 0x00, 0x00,
-            );
-            let a = _mm_and_si128(a, and_mask);
-            eprintln!("a&mask\t: {:02x}", as_portable_simd(a));
-
-            // shift left by 4bits so that we can do a logical OR
-            // to pack together 2 bytes into 1 byte
-            let b = _mm_slli_epi16(a, 4);
-            eprintln!("a<<4\t: {:02x}", as_portable_simd(b));
-
-            // shift right by 1 byte so that things line up to do
-            // the OR operation
-            let b = _mm_slli_si128(b, 1);
-            eprintln!("b>>8\t: {:02x}", as_portable_simd(b));
-
-            // do the OR operation such that two adjacent bytes
-            // end up getting packed together into one byte
-            let a = _mm_or_si128(a, b);
-            eprintln!("a|b\t: {:02x}", as_portable_simd(a));
-
-            let and_mask = _mm_setr_epi8(-1, 0, -1, 0, -1, 0, -1, 0, -1, 0, -1, 0, -1, 0, -1, 0);
-            let a = _mm_and_si128(a, and_mask);
-            eprintln!("a&mask\t: {:02x}", as_portable_simd(a));
+            // move the 13 digits to the end of the register, zeroing
+            // out the three bytes that are not part of the number
+            let shuffle = _mm_setr_epi8(-128, -128, -128, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12);
+            let a = _mm_shuffle_epi8(a, shuffle);
+            eprintln!("align\t: {:02x}", as_portable_simd(a));
+
+            // multiply every even byte by 16 and add the odd byte to it
+            // so that 2 bytes get packed into 1 byte
+            let mul_16 = _mm_setr_epi8(16, 1, 16, 1, 16, 1, 16, 1, 16, 1, 16, 1, 16, 1, 16, 1);
+            let a = _mm_maddubs_epi16(a, mul_16);
+            eprintln!("madd\t: {:02x}", as_portable_simd(a));
+
             let b = _mm_packus_epi16(a, a);
             eprintln!("pack\t: {:02x}", as_portable_simd(b));
 
             let b = _mm_cvtsi128_si64(b).swap_bytes() as u64;
             eprintln!("b\t: {:#02x}", b);
 
-            assert_eq!(0x01_23_45_67_89_12_34_00, b);
+            assert_eq!(0x00_01_23_45_67_89_12_34, b);
         }
     }
+
+    #[test]
+    fn test_line_widths() {
+        let input = "9999999999999999\n0000000000000042\n1671670171236000\n";
+        let mut parsed = Vec::new();
+        let parse = packed_4bit_parser(17).unwrap();
+        parse(input.as_bytes(), &mut parsed);
+        assert_eq!(vec![0x9999999999999999, 0x42, 0x1671670171236000], parsed);
+
+        let parse = packed_4bit_parser(11).unwrap();
+        parse(b"1671670171\n0000000042\n", &mut parsed);
+        assert_eq!(vec![0x1671670171, 0x42], parsed);
+
+        assert!(packed_4bit_parser(MAX_LINE_WIDTH + 1).is_none());
+    }
+
+    #[test]
+    fn test_wide_keys() {
+        let input = "1671670171236000000\n0000000000000000042\n9999999999999999999\n";
+        let mut parsed = Vec::new();
+        let parse = u128::parser(20).unwrap();
+        parse(input.as_bytes(), &mut parsed);
+        assert_eq!(
+            vec![0x1671670171236000000, 0x42, 0x9999999999999999999],
+            parsed
+        );
+
+        let mut parsed = Vec::new();
+        let parse = u128::parser(33).unwrap();
+        parse(b"12345678901234567890123456789012\n", &mut parsed);
+        assert_eq!(vec![0x12345678901234567890123456789012], parsed);
+
+        // narrow lines still get the same form, just widened
+        let parse = u128::parser(14).unwrap();
+        parse(b"1671670171236\n", &mut parsed);
+        assert_eq!(vec![0x1671670171236], parsed);
+
+        assert!(u128::parser(MAX_WIDE_LINE_WIDTH + 1).is_none());
+
+        assert_eq!(
+            Some(1671670171236000000),
+            0x1671670171236000000_u128.to_u64()
+        );
+        assert_eq!(Some(1671670171236), 0x1671670171236_u64.to_u64());
+        // 20 digits are too many for a u64, but not for a u128
+        let wide = 0x99999999999999999999_u128;
+        assert_eq!(None, wide.to_u64());
+        assert_eq!(99999999999999999999, wide.to_u128());
+        assert!(wide.millis("--iso").is_err());
+    }
 }
@@ -0,0 +1,163 @@
+use std::{cmp::Ordering, fmt, io};
+
+use crate::{
+    iodirect::{
+        record_file::{detect_key_digits, RecordFile},
+        sorted_file::{detect_line_width, SortedFile},
+        MergeInput, Order,
+    },
+    simd_decimal::PackedKey,
+};
+
+/// What a file holds, as far as merging it goes.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub lines: u64,
+    pub bytes: u64,
+    /// Keys of the first and last line, as written in the file.
+    pub first: Option<String>,
+    pub last: Option<String>,
+    /// Lines with the same key as the line before them.
+    pub duplicates: u64,
+    /// Order of the keys, or None if they go both ways.
+    pub order: Option<Order>,
+    /// Line at which the keys first go against the order of the lines
+    /// before it, starting at 1.
+    pub unsorted_at: Option<u64>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} lines, {} bytes", self.lines, self.bytes)?;
+        if let (Some(first), Some(last)) = (&self.first, &self.last) {
+            write!(f, ", keys {first} to {last}")?;
+        }
+        match (self.order, self.unsorted_at) {
+            (Some(Order::Ascending), _) => write!(f, ", ascending")?,
+            (Some(Order::Descending), _) => write!(f, ", descending")?,
+            (None, Some(line)) => write!(f, ", unsorted from line {line}")?,
+            (None, None) => {}
+        }
+        write!(f, ", {} duplicates", self.duplicates)
+    }
+}
+
+/// Runs `stats [--records] <file>...`, printing a summary of every file.
+pub fn run(paths: &[String], records: bool) -> io::Result<()> {
+    for path in paths {
+        let summary = if records {
+            summarize_records(path)?
+        } else {
+            summarize_lines(path)?
+        };
+        println!("{path}: {summary}");
+    }
+    Ok(())
+}
+
+fn summarize_lines(path: &str) -> io::Result<Summary> {
+    let width = detect_line_width(path)?;
+    if width > u64::MAX_LINE_WIDTH {
+        summarize(SortedFile::<u128>::open(path, Some(width))?)
+    } else {
+        summarize(SortedFile::<u64>::open(path, Some(width))?)
+    }
+}
+
+fn summarize_records(path: &str) -> io::Result<Summary> {
+    let digits = detect_key_digits(path)?;
+    if digits >= u64::MAX_LINE_WIDTH {
+        summarize(RecordFile::<u128>::open(path, Some(digits))?)
+    } else {
+        summarize(RecordFile::<u64>::open(path, Some(digits))?)
+    }
+}
+
+/// Reads all of `input` and summarizes it.
+pub fn summarize<I: MergeInput>(mut input: I) -> io::Result<Summary> {
+    let key_of = |line: &[u8]| String::from_utf8_lossy(leading_digits(line)).into_owned();
+
+    let mut summary = Summary {
+        bytes: input.file_size(),
+        ..Default::default()
+    };
+    // direction of the keys so far, equal until the first change
+    let mut direction = Ordering::Equal;
+    let mut prev = None;
+    let mut last = Vec::new();
+    while let Some(&key) = input.peek() {
+        let line = input.peek_bytes().unwrap();
+        summary.lines += 1;
+        if summary.first.is_none() {
+            summary.first = Some(key_of(line));
+        }
+        if let Some(prev) = prev {
+            match key.cmp(&prev) {
+                Ordering::Equal => summary.duplicates += 1,
+                ord if direction == Ordering::Equal => direction = ord,
+                ord if ord != direction && summary.unsorted_at.is_none() => {
+                    summary.unsorted_at = Some(summary.lines)
+                }
+                _ => {}
+            }
+        }
+        prev = Some(key);
+        last.clear();
+        last.extend_from_slice(leading_digits(line));
+        input.next()?;
+    }
+    if summary.lines > 0 {
+        summary.last = Some(key_of(&last));
+    }
+
+    summary.order = match (direction, summary.unsorted_at) {
+        (_, Some(_)) => None,
+        (Ordering::Less, None) => Some(Order::Descending),
+        _ => Some(Order::Ascending),
+    };
+    Ok(summary)
+}
+
+fn leading_digits(line: &[u8]) -> &[u8] {
+    let digits = line.iter().take_while(|b| b.is_ascii_digit()).count();
+    &line[..digits]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        let summary = |contents: &str| {
+            let path = crate::test_path("summary.txt");
+            fs::write(&path, contents).unwrap();
+            summarize_lines(path.to_str().unwrap()).unwrap()
+        };
+
+        assert_eq!(
+            Summary {
+                lines: 4,
+                bytes: 15,
+                first: Some("003".into()),
+                last: Some("010".into()),
+                duplicates: 1,
+                order: Some(Order::Ascending),
+                unsorted_at: None,
+            },
+            summary("003\n004\n004\n010")
+        );
+        let descending = summary("9\n5\n5\n1\n");
+        assert_eq!(Some(Order::Descending), descending.order);
+        assert_eq!(
+            "4 lines, 8 bytes, keys 9 to 1, descending, 1 duplicates",
+            descending.to_string()
+        );
+        let unsorted = summary("1\n1\n3\n2\n4\n");
+        assert_eq!(None, unsorted.order);
+        assert_eq!(Some(4), unsorted.unsorted_at);
+        assert_eq!(0, summary("").lines);
+    }
+}
@@ -0,0 +1,453 @@
+use std::{
+    fmt, fs,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use crate::{
+    iodirect::{
+        checksum::Checksums,
+        detect_order,
+        record_file::{detect_key_digits, RecordFile},
+        sorted_file::{detect_line_width, SortedFile},
+        InputStats, MergeInput, Order,
+    },
+    merge::SortingWriter,
+    query::packed,
+    simd_decimal::PackedKey,
+};
+
+/// Runs `verify [--records] <file> [<input>...]`.
+///
+/// With only a file, recomputes its checksums and compares them with its
+/// `.crc32c` sidecar, as written by `OutputFile` with checksums enabled,
+/// and reports the byte ranges of the chunks that differ.
+///
+/// With inputs, checks that the file is a merge of them instead: sorted,
+/// in either order, and holding exactly the lines of the inputs. Reports
+/// the first line where that stops being the case.
+///
+/// Returns whether the file passed, or why it could not be checked.
+pub fn run(path: &str, inputs: &[String], records: bool) -> io::Result<bool> {
+    if inputs.is_empty() {
+        return verify_checksums(Path::new(path));
+    }
+
+    let res = if records {
+        verify_records(path, inputs, detect_key_digits(path)?)?
+    } else {
+        let widths = inputs
+            .iter()
+            .map(|input| detect_line_width(input))
+            .collect::<io::Result<Vec<_>>>()?;
+        verify_lines(path, inputs, &widths)?
+    };
+    match res {
+        Ok(lines) => {
+            println!(
+                "{path}: OK, {lines} lines merged from {} inputs",
+                inputs.len()
+            );
+            Ok(true)
+        }
+        Err(divergence) => {
+            println!("{path}:{divergence}");
+            Ok(false)
+        }
+    }
+}
+
+/// Where and how a file stops being the merge of its inputs.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// Line of the file, starting at 1.
+    pub line: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.reason)
+    }
+}
+
+/// What verifying found, unless reading the files failed.
+type Verified = io::Result<Result<u64, Divergence>>;
+
+fn verify_lines(path: &str, inputs: &[String], widths: &[usize]) -> Verified {
+    if widths.iter().any(|&w| w != widths[0]) {
+        return verify_mixed_lines(path, inputs, widths);
+    }
+    if widths[0] > u64::MAX_LINE_WIDTH {
+        verify_lines_with::<u128>(path, inputs, widths[0])
+    } else {
+        verify_lines_with::<u64>(path, inputs, widths[0])
+    }
+}
+
+fn verify_lines_with<K: PackedKey>(path: &str, inputs: &[String], width: usize) -> Verified {
+    let order = detect_order(path)?;
+    let output = SortedFile::<K>::open(path, Some(width))?;
+    let inputs = inputs
+        .iter()
+        .map(|input| {
+            if detect_order(input)? == order {
+                SortedFile::open(input, Some(width))
+            } else {
+                SortedFile::open_backwards(input, Some(width))
+            }
+        })
+        .collect::<io::Result<_>>()?;
+    verify_merge(output, inputs, order)
+}
+
+/// Inputs of different widths merge into an output whose lines vary in
+/// width, so it gets read line by line, and the inputs each with their
+/// own width. Keys compare by value, as in the merge.
+fn verify_mixed_lines(path: &str, inputs: &[String], widths: &[usize]) -> Verified {
+    let order = detect_order(path)?;
+    let output = MixedLines::open(path)?;
+    let inputs = inputs
+        .iter()
+        .zip(widths)
+        .map(|(input, &width)| {
+            if detect_order(input)? == order {
+                SortedFile::<u128>::open(input, Some(width))
+            } else {
+                SortedFile::open_backwards(input, Some(width))
+            }
+        })
+        .collect::<io::Result<_>>()?;
+    verify_merge(output, inputs, order)
+}
+
+/// Lines of any width up to the widest key, read with plain buffered
+/// reads, which is plenty for a single pass.
+struct MixedLines {
+    path: String,
+    reader: BufReader<fs::File>,
+    file_size: u64,
+    line: Vec<u8>,
+    key: Option<u128>,
+    stats: InputStats,
+}
+
+impl MixedLines {
+    fn open(path: &str) -> io::Result<Self> {
+        let file = fs::File::open(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("failed to open {path}: {e}")))?;
+        let mut lines = Self {
+            path: path.to_string(),
+            file_size: file.metadata()?.len(),
+            reader: BufReader::with_capacity(1 << 20, file),
+            line: Vec::new(),
+            key: None,
+            stats: InputStats::default(),
+        };
+        lines.next()?;
+        Ok(lines)
+    }
+}
+
+impl MergeInput for MixedLines {
+    type Key = u128;
+
+    fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    fn peek(&self) -> Option<&u128> {
+        self.key.as_ref()
+    }
+
+    fn peek_bytes(&self) -> Option<&[u8]> {
+        self.key.map(|_| &self.line[..])
+    }
+
+    fn next(&mut self) -> io::Result<()> {
+        self.line.clear();
+        let len = self.reader.read_until(b'\n', &mut self.line)?;
+        if len == 0 {
+            self.key = None;
+            return Ok(());
+        }
+        self.stats.lines += 1;
+        self.stats.bytes += len as u64;
+        let digits = self.line.iter().take_while(|b| b.is_ascii_digit()).count();
+        if digits == 0 || digits >= u128::MAX_LINE_WIDTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}:{}: lines need 1 to {} digits",
+                    self.path,
+                    self.stats.lines,
+                    u128::MAX_LINE_WIDTH - 1
+                ),
+            ));
+        }
+        self.key = Some(packed(&self.line[..digits]));
+        Ok(())
+    }
+
+    fn stats(&self) -> InputStats {
+        self.stats
+    }
+}
+
+fn verify_records(path: &str, inputs: &[String], digits: usize) -> Verified {
+    if digits >= u64::MAX_LINE_WIDTH {
+        verify_records_with::<u128>(path, inputs, digits)
+    } else {
+        verify_records_with::<u64>(path, inputs, digits)
+    }
+}
+
+fn verify_records_with<K: PackedKey>(path: &str, inputs: &[String], digits: usize) -> Verified {
+    let order = detect_order(path)?;
+    let output = RecordFile::<K>::open(path, Some(digits))?;
+    let inputs = inputs
+        .iter()
+        .map(|input| {
+            if detect_order(input)? != order {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{input}: record mode needs inputs sorted in the output order"),
+                ));
+            }
+            RecordFile::open(input, None)
+        })
+        .collect::<io::Result<_>>()?;
+    verify_merge(output, inputs, order)
+}
+
+/// Walks `output` and the merge of `inputs` side by side. Lines with the
+/// same key may come in any order, so they get compared as a multiset,
+/// one run of equal keys at a time. Memory use is bounded by the longest
+/// such run. Returns the number of lines on success.
+pub fn verify_merge<O, I>(mut output: O, inputs: Vec<I>, order: Order) -> Verified
+where
+    O: MergeInput,
+    I: MergeInput<Key = O::Key>,
+{
+    let mut merged = SortingWriter::new(inputs).with_order(order);
+    let before = |a: &O::Key, b: &O::Key| match order {
+        Order::Ascending => a < b,
+        Order::Descending => a > b,
+    };
+    let show = |line: Option<&[u8]>| match line {
+        Some(line) => String::from_utf8_lossy(trim_newline(line)).into_owned(),
+        None => "the end of the file".to_string(),
+    };
+
+    // lines of the output before the current one
+    let mut lines = 0;
+    let mut prev = None;
+    let mut got = Vec::new();
+    let mut expected = Vec::new();
+    loop {
+        let idx = merged.pick_next();
+        let input = idx.map(|idx| &merged.inputs[idx]);
+        let want = input.and_then(|input| input.peek().copied());
+        let have = output.peek().copied();
+        let diverge = |reason| {
+            Ok(Err(Divergence {
+                line: lines + 1,
+                reason,
+            }))
+        };
+
+        let key = match (have, want) {
+            (None, None) => return Ok(Ok(lines)),
+            (Some(have), _) if matches!(prev, Some(prev) if before(&have, &prev)) => {
+                return diverge(format!("{} is out of order", show(output.peek_bytes())))
+            }
+            (Some(have), Some(want)) if have == want => have,
+            _ => {
+                return diverge(format!(
+                    "expected {}, found {}",
+                    show(input.and_then(MergeInput::peek_bytes)),
+                    show(output.peek_bytes())
+                ))
+            }
+        };
+
+        // the whole run of the key, on both sides
+        got.clear();
+        while output.peek() == Some(&key) {
+            got.push(trim_newline(output.peek_bytes().unwrap()).to_vec());
+            output.next()?;
+        }
+        expected.clear();
+        while let Some(idx) = merged.pick_next() {
+            let input = &mut merged.inputs[idx];
+            if input.peek() != Some(&key) {
+                break;
+            }
+            expected.push(trim_newline(input.peek_bytes().unwrap()).to_vec());
+            input.next()?;
+        }
+        if got.len() != expected.len() {
+            return diverge(format!(
+                "{} lines with the key of {}, expected {}",
+                got.len(),
+                show(Some(&got[0])),
+                expected.len()
+            ));
+        }
+        got.sort_unstable();
+        expected.sort_unstable();
+        if let Some((g, e)) = got.iter().zip(&expected).find(|(g, e)| g != e) {
+            return diverge(format!(
+                "run of {} lines with the same key has {} instead of {}",
+                got.len(),
+                show(Some(g)),
+                show(Some(e))
+            ));
+        }
+        lines += got.len() as u64;
+        prev = Some(key);
+    }
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\n").unwrap_or(line)
+}
+
+/// A file without a readable sidecar fails, since there is nothing to
+/// check it against. Only failing to read the file itself is an error.
+fn verify_checksums(path: &Path) -> io::Result<bool> {
+    let sidecar = Checksums::sidecar_path(path);
+    let expected = match fs::read_to_string(&sidecar).and_then(|s| Checksums::parse(&s)) {
+        Ok(expected) => expected,
+        Err(e) => {
+            println!(
+                "{}: FAILED, no checksums in {}: {e}",
+                path.display(),
+                sidecar.display()
+            );
+            return Ok(false);
+        }
+    };
+    let actual = Checksums::of_file(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to read {}: {e}", path.display())))?;
+
+    if expected == actual {
+        println!("{}: OK, crc32c {:08x}", path.display(), actual.crc);
+        return Ok(true);
+    }
+    println!(
+        "{}: FAILED, crc32c {:08x} with {} bytes, expected {:08x} with {} bytes",
+        path.display(),
+        actual.crc,
+        actual.len,
+        expected.crc,
+        expected.len
+    );
+    for (start, end) in expected.damaged_ranges(&actual) {
+        println!("{}: bytes {start}..{end} differ", path.display());
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::iodirect::output_file::{OutputFile, OutputOptions};
+
+    use super::*;
+
+    #[test]
+    fn test_verify_merge() {
+        let write = |name: &str, contents: &str| {
+            let path = crate::test_path(&format!("verify.{name}.txt"));
+            fs::write(&path, contents).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        let inputs = [
+            write("a", "1671670171235\n1671670171237\n1671670171237"),
+            // descending, gets read backwards
+            write("b", "1671670171238\n1671670171237\n1671670171236\n"),
+        ];
+        let verify =
+            |output: &str| verify_lines(&write("out", output), &inputs, &[14, 14]).unwrap();
+
+        let merged = "1671670171235\n1671670171236\n1671670171237\n\
+                      1671670171237\n1671670171237\n1671670171238\n";
+        assert_eq!(Ok(6), verify(merged));
+        let descending: String = merged.split_inclusive('\n').rev().collect();
+        assert_eq!(Ok(6), verify(&descending));
+
+        let line = |output: &str| verify(output).unwrap_err().line;
+        // a missing line, an extra one and lines out of order
+        assert_eq!(2, line(&merged.replace("1671670171236\n", "")));
+        assert_eq!(3, line(&merged.replacen("1671670171237\n", "", 1)));
+        assert_eq!(7, line(&format!("{merged}1671670171239\n")));
+        assert_eq!(
+            1,
+            line(&merged.replacen("1671670171235", "1671670171236", 1))
+        );
+        assert_eq!(7, line(&format!("{merged}1671670171235\n")));
+
+        // lines with equal keys may come in any order, but have to be
+        // the same lines
+        let inputs = [
+            write("ra", "1671670171235\ta\n1671670171236\tb\n"),
+            write("rb", "1671670171236\tc\n"),
+        ];
+        let verify = |output: &str| verify_records(&write("rout", output), &inputs, 13).unwrap();
+        assert_eq!(
+            Ok(3),
+            verify("1671670171235\ta\n1671670171236\tc\n1671670171236\tb\n")
+        );
+        let divergence = verify("1671670171235\ta\n1671670171236\tc\n1671670171236\tc\n");
+        assert_eq!(2, divergence.unwrap_err().line);
+
+        // inputs of different widths compare by value
+        let inputs = [
+            write("wa", "0100\n0900\n"),
+            write("wb", "99\n"),
+            write("wc", "1671670171235\n"),
+        ];
+        let verify = |output: &str| {
+            let widths = inputs.iter().map(|i| detect_line_width(i).unwrap());
+            verify_lines(&write("wout", output), &inputs, &widths.collect::<Vec<_>>()).unwrap()
+        };
+        assert_eq!(Ok(4), verify("99\n0100\n0900\n1671670171235\n"));
+        assert_eq!(Ok(4), verify("1671670171235\n0900\n0100\n99\n"));
+        assert_eq!(
+            2,
+            verify("99\n0900\n0100\n1671670171235\n").unwrap_err().line
+        );
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        let path = crate::test_path("verify.txt");
+        let opts = OutputOptions {
+            checksum: true,
+            ..Default::default()
+        };
+        let mut output = OutputFile::new_atomic_with(path.to_str().unwrap(), 0, opts).unwrap();
+        for i in 0..200_000_u64 {
+            output.write_u64(1671670171000 + i).unwrap();
+        }
+        output.finish().unwrap();
+        assert!(verify_checksums(&path).unwrap());
+
+        // flip a byte in the third chunk
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[2 * (1 << 20) + 5] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        let expected = fs::read_to_string(Checksums::sidecar_path(&path)).unwrap();
+        let expected = Checksums::parse(&expected).unwrap();
+        let actual = Checksums::of_file(&path).unwrap();
+        assert_eq!(
+            vec![(2 << 20, bytes.len() as u64)],
+            expected.damaged_ranges(&actual)
+        );
+        assert!(!verify_checksums(&path).unwrap());
+
+        // without a sidecar there is nothing to pass
+        fs::remove_file(Checksums::sidecar_path(&path)).unwrap();
+        assert!(!verify_checksums(&path).unwrap());
+    }
+}